@@ -0,0 +1,39 @@
+//Admin only pages. Every route in here sits behind require_role(Admin).
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::{Html, IntoResponse},
+    Json,
+};
+use tower_cookies::Cookies;
+
+use crate::{get_time, session, users};
+
+///calls the admin.html
+pub async fn admin_page() -> Html<&'static str> {
+    Html(include_str!("../admin.html"))
+}
+
+///Lists every logged in session.
+///
+/// * `cookies` - used to mark which session is the admin's own
+pub async fn list_sessions(cookies: Cookies) -> Json<Vec<session::SessionInfo>> {
+    let current = cookies.get(session::SESSION_COOKIE).map(|c| c.value().to_string());
+    Json(session::SESSIONS.list(current.as_deref()))
+}
+
+///Kicks a session out. For when a shared device walks off.
+///
+/// * `handle` - the public handle from list_sessions, not the real session id
+pub async fn revoke_session(
+    Extension(admin): Extension<users::AuthUser>,
+    Path(handle): Path<String>,
+) -> impl IntoResponse {
+    match session::SESSIONS.revoke(&handle) {
+        Some(revoked) => {
+            println!("'{}' revoked the session of '{}' ({}) on {}", admin.name, revoked.user, revoked.ip, get_time());
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "Session not found").into_response(),
+    }
+}
@@ -0,0 +1,335 @@
+//Checksums for everything in uploads, worked out while the bytes come in and kept in checksums.json.
+//They go out in /files and on downloads, clients can send one to have their upload checked,
+//and an admin can re-read everything on disk to catch bit rot.
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::Path,
+    sync::{Mutex, atomic::{AtomicBool, Ordering}},
+    time::UNIX_EPOCH,
+};
+use axum::{
+    extract::Extension,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{CONFIG, files, get_time, upload_error::UploadError, users};
+
+const CHECKSUMS_PATH: &str = "checksums.json";
+
+///The header a client sends its own SHA-256 in, as hex.
+pub const EXPECTED_HEADER: &str = "X-Checksum-Sha256";
+
+///What a file hashed to, as hex. BLAKE3 only when `blake3= on` in config.ini.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Digests {
+    pub sha256: String,
+    pub blake3: Option<String>,
+}
+
+///Hashes bytes as they go by, so nothing has to be read twice.
+pub struct Hasher {
+    sha256: Sha256,
+    blake3: Option<blake3::Hasher>,
+}
+
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher { sha256: Sha256::new(), blake3: CONFIG.checksum_blake3.then(blake3::Hasher::new) }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.sha256.update(bytes);
+        if let Some(blake3) = &mut self.blake3 {
+            blake3.update(bytes);
+        }
+    }
+
+    pub fn finish(self) -> Digests {
+        Digests {
+            sha256: hex(&self.sha256.finalize()),
+            blake3: self.blake3.map(|b| b.finalize().to_hex().to_string()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+///Reads a whole file and hashes it. Blocking, so call it from spawn_blocking.
+pub fn hash_file(path: &Path) -> std::io::Result<Digests> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0u8; 128 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+///One file's entry in checksums.json.
+///
+/// * `size` / `modified_ns` - what the file looked like when it was hashed. If either changed,
+///   the entry is stale and gets ignored.
+/// * `checked_at` - unix seconds, the last time the bytes were hashed
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checksum {
+    pub sha256: String,
+    pub blake3: Option<String>,
+    size: u64,
+    modified_ns: u64,
+    checked_at: i64,
+}
+
+impl From<Checksum> for Digests {
+    fn from(checksum: Checksum) -> Self {
+        Digests { sha256: checksum.sha256, blake3: checksum.blake3 }
+    }
+}
+
+///Size and modified time, to tell if an entry still belongs to the file on disk.
+fn stamp(path: &Path) -> Option<(u64, u64)> {
+    let meta = fs::metadata(path).ok().filter(|m| m.is_file())?;
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((meta.len(), modified.as_nanos() as u64))
+}
+
+///Every known checksum, keyed by path from the top of uploads.
+///
+/// * `unsaved` - set when something changed since checksums.json was last written. Every upload and every file
+///   verify records one, so writing the whole file each time adds up. flush() writes it out, from the cleanup
+///   ticker and on shutdown. Anything lost in a crash just gets hashed again when it's next needed.
+pub struct ChecksumStore {
+    files: Mutex<HashMap<String, Checksum>>,
+    unsaved: AtomicBool,
+}
+
+pub static CHECKSUMS: Lazy<ChecksumStore> = Lazy::new(|| {
+    let files: HashMap<String, Checksum> = fs::read_to_string(CHECKSUMS_PATH)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    ChecksumStore { files: Mutex::new(files), unsaved: AtomicBool::new(false) }
+});
+
+impl ChecksumStore {
+    fn save(files: &HashMap<String, Checksum>) {
+        match serde_json::to_string_pretty(files) {
+            Ok(json) => {
+                if let Err(e) = fs::write(CHECKSUMS_PATH, json) {
+                    println!("ERROR! Could not save {}: {}", CHECKSUMS_PATH, e);
+                }
+            }
+            Err(e) => println!("ERROR! Could not save {}: {}", CHECKSUMS_PATH, e),
+        }
+    }
+
+    ///Saves what a file in uploads hashed to. Call it once the file is in place.
+    pub fn record(&self, path: &Path, digests: &Digests) {
+        let (Some(key), Some((size, modified_ns))) = (files::uploads_key(path), stamp(path)) else {
+            return;
+        };
+        let entry = Checksum {
+            sha256: digests.sha256.clone(),
+            blake3: digests.blake3.clone(),
+            size,
+            modified_ns,
+            checked_at: chrono::Utc::now().timestamp(),
+        };
+        self.files.lock().unwrap().insert(key, entry);
+        self.unsaved.store(true, Ordering::Relaxed);
+    }
+
+    ///Writes checksums.json if anything changed since the last time.
+    pub fn flush(&self) {
+        if self.unsaved.swap(false, Ordering::Relaxed) {
+            Self::save(&self.files.lock().unwrap());
+        }
+    }
+
+    ///The checksum for a file in uploads, if there is one and the file hasn't changed since.
+    pub fn known(&self, path: &Path) -> Option<Checksum> {
+        let key = files::uploads_key(path)?;
+        let (size, modified_ns) = stamp(path)?;
+        let files = self.files.lock().unwrap();
+        files.get(&key).filter(|c| c.size == size && c.modified_ns == modified_ns).cloned()
+    }
+
+    ///The checksum for a file in uploads, hashing it now if it has none yet. Blocking.
+    pub fn get_or_hash(&self, path: &Path) -> Option<Checksum> {
+        if let Some(known) = self.known(path) {
+            return Some(known);
+        }
+        let digests = hash_file(path).ok()?;
+        self.record(path, &digests);
+        self.known(path)
+    }
+
+    ///Keeps the checksums with a file or folder when it gets renamed or moved.
+    pub fn moved(&self, from: &str, to: &str) {
+        if files::rekey(&mut self.files.lock().unwrap(), from, to) {
+            self.unsaved.store(true, Ordering::Relaxed);
+        }
+    }
+
+    ///Forgets a file or folder that was deleted for good.
+    pub fn removed(&self, key: &str) {
+        let mut files = self.files.lock().unwrap();
+        let before = files.len();
+        files.retain(|k, _| !Path::new(k).starts_with(key));
+        if files.len() < before {
+            self.unsaved.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+///Reads the checksum a client wants its upload to match, from X-Checksum-Sha256.
+///
+/// * Returns Ok(None) if there is none, and BadRequest if it isn't 64 hex characters.
+pub fn expected(headers: &HeaderMap) -> Result<Option<String>, UploadError> {
+    let Some(value) = headers.get(EXPECTED_HEADER) else {
+        return Ok(None);
+    };
+    let value = value.to_str().unwrap_or("").trim().to_lowercase();
+    match value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        true => Ok(Some(value)),
+        false => Err(UploadError::BadRequest("X-Checksum-Sha256 should be 64 hex characters")),
+    }
+}
+
+///Turns a missed expected checksum into the 422.
+pub fn check(expected: Option<&str>, digests: &Digests) -> Result<(), UploadError> {
+    match expected {
+        Some(sha256) if sha256 != digests.sha256 => Err(UploadError::ChecksumMismatch),
+        _ => Ok(()),
+    }
+}
+
+///The Digest header for a download, `sha-256=<base64>` like RFC 3230 wants.
+pub fn digest_header(checksum: &Checksum) -> Option<String> {
+    let bytes: Vec<u8> = (0..checksum.sha256.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(checksum.sha256.get(i..i + 2)?, 16).ok())
+        .collect::<Option<_>>()?;
+    Some(format!("sha-256={}", STANDARD.encode(bytes)))
+}
+
+#[derive(Deserialize)]
+pub struct VerifyRequest {
+    #[serde(default)]
+    path: String,
+}
+
+///A file whose bytes no longer match what they hashed to before.
+#[derive(Serialize)]
+pub struct Corrupt {
+    path: String,
+    expected: String,
+    actual: String,
+}
+
+///What POST /admin/verify found.
+///
+/// * `ok` - matched the checksum on record
+/// * `added` - had no checksum yet, or was changed since (a new upload, say). Hashed and recorded now.
+/// * `corrupt` - same size and modified time as when it was hashed, but different bytes. That is bit rot.
+/// * `unreadable` - couldn't be read at all
+#[derive(Serialize, Default)]
+pub struct VerifyReport {
+    checked: usize,
+    ok: usize,
+    added: usize,
+    corrupt: Vec<Corrupt>,
+    unreadable: Vec<String>,
+}
+
+///Goes through every file under `folder` and hashes it again. Blocking.
+fn verify_folder(folder: &Path, report: &mut VerifyReport) {
+    let Ok(entries) = fs::read_dir(folder) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        //the trash, old versions, half done uploads.
+        if files::is_own_name(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => verify_folder(&path, report),
+            Ok(t) if t.is_file() => verify_file(&path, report),
+            _ => {}
+        }
+    }
+}
+
+fn verify_file(path: &Path, report: &mut VerifyReport) {
+    let name = files::uploads_key(path).unwrap_or_else(|| path.display().to_string());
+    report.checked += 1;
+    let on_record = CHECKSUMS.known(path);
+    let digests = match hash_file(path) {
+        Ok(digests) => digests,
+        Err(e) => {
+            println!("ERROR! Could not read '{}' to verify it: {}", name, e);
+            report.unreadable.push(name);
+            return;
+        }
+    };
+    match on_record {
+        Some(record) if record.sha256 == digests.sha256 => {
+            //still good. note when we last looked.
+            CHECKSUMS.record(path, &digests);
+            report.ok += 1;
+        }
+        //the old checksum stays on record, it's the one the file should have.
+        Some(record) => {
+            println!("❌ '{}' does not match its checksum any more. It may be damaged on disk.", name);
+            report.corrupt.push(Corrupt { path: name, expected: record.sha256, actual: digests.sha256 });
+        }
+        None => {
+            CHECKSUMS.record(path, &digests);
+            report.added += 1;
+        }
+    }
+}
+
+///POST /admin/verify with `{"path": "photos"}`. Re-reads every file in a folder, or everything with no path,
+///and compares it to its checksum. Admins only, since it reads every byte.
+pub async fn verify(Extension(user): Extension<users::AuthUser>, Json(request): Json<VerifyRequest>) -> Response {
+    let Some(target) = files::resolve(&request.path, &user) else {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    };
+    println!("🔎 '{}' started verifying '{}' on {}", user.name, request.path, get_time());
+    let report = tokio::task::spawn_blocking(move || {
+        let mut report = VerifyReport::default();
+        match target.is_dir() {
+            true => verify_folder(&target, &mut report),
+            false => verify_file(&target, &mut report),
+        }
+        report
+    })
+    .await
+    .unwrap_or_default();
+    CHECKSUMS.flush();
+    println!(
+        "🔎 Verified {} files on {}: {} ok, {} new, {} damaged, {} unreadable",
+        report.checked,
+        get_time(),
+        report.ok,
+        report.added,
+        report.corrupt.len(),
+        report.unreadable.len()
+    );
+    Json(report).into_response()
+}
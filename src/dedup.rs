@@ -0,0 +1,153 @@
+//Deduplicated storage, for `dedup= on` in config.ini. Each distinct file is kept once in uploads/.blobs under its
+//SHA-256, and every name in uploads is a hard link to it. The file system already counts the links, so that is the
+//reference count: a blob with a count of 1 is only linked from .blobs itself, and the cleanup ticker deletes it.
+//Names in the trash and old versions are links too, so their blobs stay until those are gone.
+//Nothing in rShare writes into a file in place (uploads always land through a rename), so shared blobs never change.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{CONFIG, checksums, files, get_time, staging};
+
+///Where the contents live. Hidden, so it never shows up in the list or as a download.
+fn blobs_dir() -> PathBuf {
+    Path::new(files::UPLOADS_DIR).join(".blobs")
+}
+
+///How many names point at the same bytes. Only unix lets us ask.
+#[cfg(unix)]
+fn link_count(meta: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.nlink())
+}
+
+#[cfg(not(unix))]
+fn link_count(_meta: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+///Checks two paths are one file on disk already.
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    false
+}
+
+///If dedup can run here. It needs link counts, which only unix gives us.
+pub fn enabled() -> bool {
+    CONFIG.dedup && cfg!(unix)
+}
+
+///Swaps a file that was just stored for a link to the blob with the same bytes, or makes it the blob if it's the first.
+///
+/// * `path` - the file, already at its real name in uploads
+/// * `sha256` - what it hashed to
+/// * Returns how many bytes it saved, 0 if the bytes weren't there before.
+/// * The name goes to the blob with one rename, so it never goes missing. Its modified time becomes the blob's,
+///   so record its checksum after this, not before.
+pub fn store(path: &Path, sha256: &str) -> u64 {
+    if !enabled() {
+        return 0;
+    }
+    match share(path, sha256) {
+        Ok(saved) => saved,
+        Err(e) => {
+            println!("ERROR! Could not deduplicate '{}': {}", path.display(), e);
+            0
+        }
+    }
+}
+
+fn share(path: &Path, sha256: &str) -> std::io::Result<u64> {
+    fs::create_dir_all(blobs_dir())?;
+    let blob = blobs_dir().join(sha256);
+    //first time we see these bytes. the file itself becomes the blob.
+    match fs::hard_link(path, &blob) {
+        Ok(()) => return Ok(0),
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+        Err(_) => {}
+    }
+
+    let (file, existing) = (fs::metadata(path)?, fs::metadata(&blob)?);
+    //already one and the same, or a blob that doesn't look like these bytes any more. leave it alone.
+    if same_file(&file, &existing) || file.len() != existing.len() {
+        return Ok(0);
+    }
+    let link = staging::hidden_name(path)?;
+    fs::hard_link(&blob, &link)?;
+    if let Err(e) = fs::rename(&link, path) {
+        let _ = fs::remove_file(&link);
+        return Err(e);
+    }
+    Ok(file.len())
+}
+
+///Deletes blobs nothing links to any more. Called from the cleanup ticker, even with dedup off,
+///so blobs from when it was on still go away once their files do.
+pub async fn collect_garbage() {
+    let (removed, freed) = tokio::task::spawn_blocking(|| {
+        let (mut removed, mut freed) = (0, 0);
+        let Ok(entries) = fs::read_dir(blobs_dir()) else {
+            return (0, 0);
+        };
+        for entry in entries.flatten() {
+            let Ok(meta) = entry.metadata() else { continue };
+            if meta.is_file() && link_count(&meta) == Some(1) && fs::remove_file(entry.path()).is_ok() {
+                removed += 1;
+                freed += meta.len();
+            }
+        }
+        (removed, freed)
+    })
+    .await
+    .unwrap_or_default();
+    if removed > 0 {
+        println!("🧹 Deleted {} unused blob(s), freeing {:.2} MB on {}", removed, freed as f64 / (1024.0 * 1024.0), get_time());
+    }
+}
+
+///Links up duplicates that were uploaded before dedup was turned on. Called once at startup. Blocking.
+///
+/// * Files without a checksum yet get hashed, so the first run can take a while on a big uploads folder.
+pub fn dedup_existing() {
+    if !enabled() {
+        return;
+    }
+    let (mut linked, mut saved) = (0, 0);
+    dedup_folder(Path::new(files::UPLOADS_DIR), &mut linked, &mut saved);
+    if linked > 0 {
+        println!("🔗 Deduplicated {} file(s), freeing {:.2} MB on {}", linked, saved as f64 / (1024.0 * 1024.0), get_time());
+    }
+}
+
+fn dedup_folder(folder: &Path, linked: &mut usize, saved: &mut u64) {
+    let Ok(entries) = fs::read_dir(folder) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        //the blobs themselves live in one of rShare's own folders.
+        if files::is_own_name(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => dedup_folder(&path, linked, saved),
+            Ok(t) if t.is_file() => {
+                let Some(checksum) = checksums::CHECKSUMS.get_or_hash(&path) else { continue };
+                let bytes = store(&path, &checksum.sha256);
+                checksums::CHECKSUMS.record(&path, &checksum.into());
+                if bytes > 0 {
+                    *linked += 1;
+                    *saved += bytes;
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//Drop box links. The opposite of a share link: guests can upload through it but never see anything.
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    sync::Mutex,
+};
+use axum::{
+    extract::{ConnectInfo, Extension, Multipart, Path},
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{checksums, expiry, files, format_time, get_time, quota, session, stream_file, throttle, upload_failed, users, write_field, upload_error::UploadError};
+
+const DROPBOXES_PATH: &str = "dropboxes.json";
+
+///One drop box link, as saved in dropboxes.json.
+///
+/// * `token` - the random part of the `/r/{token}` url
+/// * `folder` - the folder inside uploads where guest files land
+/// * `expires_at` - unix seconds. None means it never expires.
+/// * `max_bytes` - total bytes guests can send through this link. None means only the server limit.
+/// * `max_files` - how many files guests can send. None means no limit.
+/// * `bytes_reserved` - held for files still streaming in, so two guests at once can't both get the same room
+/// * `revoked` - turned off by an admin. It's kept so its folder stays the admin's.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DropBox {
+    pub token: String,
+    pub label: String,
+    pub folder: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<i64>,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u32>,
+    pub bytes_received: u64,
+    pub files_received: u32,
+    #[serde(skip)]
+    pub bytes_reserved: u64,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+impl DropBox {
+    ///Whether guests can still use it. Expired and revoked both mean no.
+    fn is_closed(&self, now: i64) -> bool {
+        self.revoked || self.expires_at.is_some_and(|t| now >= t)
+    }
+
+    fn bytes_left(&self) -> u64 {
+        self.max_bytes.map_or(u64::MAX, |max| max.saturating_sub(self.bytes_received).saturating_sub(self.bytes_reserved))
+    }
+
+    fn files_left(&self) -> u32 {
+        self.max_files.map_or(u32::MAX, |max| max.saturating_sub(self.files_received))
+    }
+}
+
+///What the admin gets to see about a drop box.
+#[derive(Serialize)]
+pub struct DropBoxInfo {
+    pub token: String,
+    pub url: String,
+    pub label: String,
+    pub folder: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u32>,
+    pub bytes_received: u64,
+    pub files_received: u32,
+    pub revoked: bool,
+}
+
+impl From<&DropBox> for DropBoxInfo {
+    fn from(dropbox: &DropBox) -> Self {
+        DropBoxInfo {
+            token: dropbox.token.clone(),
+            url: format!("/r/{}", dropbox.token),
+            label: dropbox.label.clone(),
+            folder: dropbox.folder.clone(),
+            created_by: dropbox.created_by.clone(),
+            created_at: dropbox.created_at.clone(),
+            expires_at: dropbox.expires_at.map(format_time),
+            max_bytes: dropbox.max_bytes,
+            max_files: dropbox.max_files,
+            bytes_received: dropbox.bytes_received,
+            files_received: dropbox.files_received,
+            revoked: dropbox.revoked,
+        }
+    }
+}
+
+///Every drop box, keyed by token. Saved to dropboxes.json after each change.
+pub struct DropBoxStore {
+    boxes: Mutex<HashMap<String, DropBox>>,
+}
+
+pub static DROPBOXES: Lazy<DropBoxStore> = Lazy::new(|| {
+    let boxes: HashMap<String, DropBox> = fs::read_to_string(DROPBOXES_PATH)
+        .ok()
+        .and_then(|content| serde_json::from_str::<Vec<DropBox>>(&content).ok())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|dropbox| (dropbox.token.clone(), dropbox))
+        .collect();
+    DropBoxStore { boxes: Mutex::new(boxes) }
+});
+
+impl DropBoxStore {
+    fn save(boxes: &HashMap<String, DropBox>) {
+        let list: Vec<&DropBox> = boxes.values().collect();
+        match serde_json::to_string_pretty(&list) {
+            Ok(json) => {
+                if let Err(e) = fs::write(DROPBOXES_PATH, json) {
+                    println!("ERROR! Could not save {}: {}", DROPBOXES_PATH, e);
+                }
+            }
+            Err(e) => println!("ERROR! Could not save {}: {}", DROPBOXES_PATH, e),
+        }
+    }
+
+    fn insert(&self, dropbox: DropBox) {
+        let mut boxes = self.boxes.lock().unwrap();
+        boxes.insert(dropbox.token.clone(), dropbox);
+        Self::save(&boxes);
+    }
+
+    ///Finds a drop box guests can still use. Expired and revoked ones look the same as missing ones.
+    fn get(&self, token: &str) -> Option<DropBox> {
+        let now = chrono::Utc::now().timestamp();
+        self.boxes.lock().unwrap().get(token).filter(|d| !d.is_closed(now)).cloned()
+    }
+
+    ///Checks if a folder in uploads belongs to a drop box, expired and revoked ones included.
+    pub fn owns_folder(&self, folder: &str) -> bool {
+        self.boxes.lock().unwrap().values().any(|d| d.folder == folder)
+    }
+
+    ///Holds a file slot, and room for its bytes, for a guest before their file starts streaming.
+    ///
+    /// * `wanted` - the most the file could be, from what's left of the Content-Length. u64::MAX if there wasn't one.
+    /// * Returns the slot, with how many bytes that file may have, or None if the box is full or gone.
+    fn reserve_file(&self, token: &str, wanted: u64) -> Option<Slot> {
+        let now = chrono::Utc::now().timestamp();
+        let mut boxes = self.boxes.lock().unwrap();
+        let dropbox = boxes.get_mut(token).filter(|d| !d.is_closed(now))?;
+        if dropbox.files_left() == 0 || dropbox.bytes_left() == 0 {
+            return None;
+        }
+        dropbox.files_received += 1;
+        let limit = dropbox.bytes_left().min(wanted);
+        //no max_bytes means there's nothing to hold.
+        if dropbox.max_bytes.is_some() {
+            dropbox.bytes_reserved += limit;
+        }
+        Self::save(&boxes);
+        Some(Slot { token: token.to_string(), limit, finished: false })
+    }
+
+    ///Adds the size of a finished file to the box, or gives the slot back if it failed.
+    ///
+    /// * `reserved` - the bytes reserve_file held. They all go back, and only what really came in gets counted.
+    fn finish_file(&self, token: &str, reserved: u64, bytes: Option<u64>) {
+        let mut boxes = self.boxes.lock().unwrap();
+        if let Some(dropbox) = boxes.get_mut(token) {
+            if dropbox.max_bytes.is_some() {
+                dropbox.bytes_reserved = dropbox.bytes_reserved.saturating_sub(reserved);
+            }
+            match bytes {
+                Some(bytes) => dropbox.bytes_received += bytes,
+                None => dropbox.files_received = dropbox.files_received.saturating_sub(1),
+            }
+            Self::save(&boxes);
+        }
+    }
+
+    ///Turns a drop box off for guests. Returns it, or None if there's no such box or it was already revoked.
+    fn revoke(&self, token: &str) -> Option<DropBox> {
+        let mut boxes = self.boxes.lock().unwrap();
+        let dropbox = boxes.get_mut(token).filter(|d| !d.revoked)?;
+        dropbox.revoked = true;
+        let revoked = dropbox.clone();
+        Self::save(&boxes);
+        Some(revoked)
+    }
+
+    fn list(&self) -> Vec<DropBoxInfo> {
+        let boxes = self.boxes.lock().unwrap();
+        let mut list: Vec<DropBoxInfo> = boxes.values().map(DropBoxInfo::from).collect();
+        list.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        list
+    }
+}
+
+///One file's slot in a drop box, from reserve_file.
+///
+/// * `limit` - the most bytes the file may have. They're held for it until it's finished.
+/// * A slot dropped without finish, like when a guest hangs up halfway, goes back to the box.
+struct Slot {
+    token: String,
+    limit: u64,
+    finished: bool,
+}
+
+impl Slot {
+    ///Counts the file, or gives the slot back if `bytes` is None.
+    fn finish(mut self, bytes: Option<u64>) {
+        self.finished = true;
+        DROPBOXES.finish_file(&self.token, self.limit, bytes);
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.finished {
+            DROPBOXES.finish_file(&self.token, self.limit, None);
+        }
+    }
+}
+
+///The body of POST /dropboxes.
+///
+/// * `label` - a name for the folder, like "Office visit photos"
+/// * `expires_in_secs` - how long the link works for. Leave it out for forever.
+/// * `max_bytes` - total size guests can send. Leave it out for the server limit.
+/// * `max_files` - how many files guests can send. Leave it out for no limit.
+#[derive(Deserialize)]
+pub struct CreateDropBox {
+    label: Option<String>,
+    expires_in_secs: Option<u64>,
+    max_bytes: Option<u64>,
+    max_files: Option<u32>,
+}
+
+///Makes a new drop box link and its folder inside uploads.
+pub async fn create_dropbox(
+    Extension(user): Extension<users::AuthUser>,
+    Json(request): Json<CreateDropBox>,
+) -> Response {
+    let token = match session::random_token() {
+        Ok(token) => token,
+        Err(e) => {
+            println!("ERROR! Could not make a drop box token: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    //keep the folder name boring so it can't escape uploads. the token bit keeps it unique.
+    let label = request.label.unwrap_or_default().trim().to_string();
+    let clean_label: String = label
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == ' ')
+        .collect();
+    let clean_label = clean_label.trim();
+    let folder = if clean_label.is_empty() {
+        format!("dropbox-{}", &token[..8])
+    } else {
+        format!("{}-{}", clean_label, &token[..8])
+    };
+
+    if let Err(e) = tokio::fs::create_dir_all(std::path::Path::new(files::UPLOADS_DIR).join(&folder)).await {
+        println!("ERROR! Could not make drop box folder '{}': {}", folder, e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let expires_at = request
+        .expires_in_secs
+        .map(|secs| chrono::Utc::now().timestamp().saturating_add(secs.min(i64::MAX as u64) as i64));
+
+    let dropbox = DropBox {
+        token,
+        label,
+        folder,
+        created_by: user.name.clone(),
+        created_at: get_time(),
+        expires_at,
+        max_bytes: request.max_bytes,
+        max_files: request.max_files,
+        bytes_received: 0,
+        files_received: 0,
+        bytes_reserved: 0,
+        revoked: false,
+    };
+    let info = DropBoxInfo::from(&dropbox);
+    DROPBOXES.insert(dropbox);
+
+    println!("📥 '{}' made a drop box into '{}' on {}", user.name, info.folder, get_time());
+    (StatusCode::CREATED, Json(info)).into_response()
+}
+
+///Lists every drop box, including expired and revoked ones so the admin can still find the folder.
+pub async fn list_dropboxes() -> Json<Vec<DropBoxInfo>> {
+    Json(DROPBOXES.list())
+}
+
+///Turns off a drop box link. The files it collected stay where they are, and stay the admin's.
+pub async fn revoke_dropbox(
+    Extension(user): Extension<users::AuthUser>,
+    Path(token): Path<String>,
+) -> Response {
+    match DROPBOXES.revoke(&token) {
+        Some(dropbox) => {
+            println!("📥 '{}' closed the drop box into '{}' on {}", user.name, dropbox.folder, get_time());
+            StatusCode::NO_CONTENT.into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "Drop box not found").into_response(),
+    }
+}
+
+///Lets the admin grab a file a guest sent in.
+pub async fn download_dropbox_file(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(user): Extension<users::AuthUser>,
+    Path((token, name)): Path<(String, String)>,
+    request_headers: HeaderMap,
+) -> Response {
+    let Some(folder) = DROPBOXES.boxes.lock().unwrap().get(&token).map(|d| d.folder.clone()) else {
+        return (StatusCode::NOT_FOUND, "Drop box not found").into_response();
+    };
+    if guest_name(&name).is_none() {
+        println!("WARNING: Malicious path traversal attempt blocked: {}", name);
+        return (StatusCode::BAD_REQUEST, "Invalid filename").into_response();
+    }
+    let Some(limiter) = throttle::Limiter::download(addr.ip(), Some(&user.name)) else {
+        return throttle::too_busy();
+    };
+    stream_file(&format!("{}/{}", folder, name), &name, &request_headers, limiter).await
+}
+
+///The public upload page for a drop box.
+pub async fn open_dropbox(Path(token): Path<String>) -> Response {
+    match DROPBOXES.get(&token) {
+        Some(_) => Html(include_str!("../dropbox.html")).into_response(),
+        None => (StatusCode::NOT_FOUND, "This link doesn't exist or has expired").into_response(),
+    }
+}
+
+///Checks a file name a guest sent, or one asked for in a drop box. One plain name, and never one of rShare's own.
+fn guest_name(name: &str) -> Option<String> {
+    files::clean_path(name)
+        .filter(|rel| rel.components().count() == 1)
+        .map(|rel| files::display_path(&rel))
+}
+
+///Where a guest's file goes: its name with a random bit added, like `photo-1a2b3c4d5e6f.jpg`.
+///
+/// * Guests can't be told a name is taken, or be allowed to replace someone else's file, whatever on_collision says.
+///   A name nobody else can have does both.
+/// * Returns None if the name, before or after the random bit, isn't one guest_name lets through.
+fn guest_key(folder: &str, name: &str) -> std::io::Result<Option<String>> {
+    let Some(name) = guest_name(name) else {
+        return Ok(None);
+    };
+    let random = session::random_token().map_err(std::io::Error::other)?;
+    let name = std::path::Path::new(&name);
+    let stem = name.file_stem().and_then(|s| s.to_str()).unwrap_or("upload");
+    let stored_as = match name.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}-{}.{}", stem, &random[..12], ext),
+        None => format!("{}-{}", stem, &random[..12]),
+    };
+    Ok(guest_name(&stored_as).map(|stored_as| format!("{}/{}", folder, stored_as)))
+}
+
+///Takes files from a guest. Runs through the same write_field as the dashboard upload.
+///
+/// * Every file needs a free slot and has to fit in what is left of the byte limit.
+/// * Files get a name of their own from guest_key, and the answer only has the names the guest sent.
+pub async fn dropbox_upload(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let Some(dropbox) = DROPBOXES.get(&token) else {
+        return (StatusCode::NOT_FOUND, "This link doesn't exist or has expired").into_response();
+    };
+
+    let total_request_size: u64 = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(0);
+    let who = addr.ip().to_string();
+    if total_request_size > dropbox.bytes_left() {
+        return upload_failed(&who, "", UploadError::TooLarge);
+    }
+    //guests' files count against whoever made the drop box.
+    if let Err(e) = quota::check(Some(&dropbox.created_by), total_request_size) {
+        return upload_failed(&who, "", e);
+    }
+
+    let mut global_written: u64 = 0;
+    let Some(limiter) = throttle::Limiter::upload(addr.ip(), None) else {
+        println!("\n❌ Upload by {} through drop box '{}' turned away on {}: too many at once", addr.ip(), dropbox.label, get_time());
+        return throttle::too_busy();
+    };
+    let expected = match checksums::expected(&headers) {
+        Ok(expected) => expected,
+        Err(e) => return upload_failed(&who, "", e),
+    };
+    let mut received = vec![];
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return upload_failed(&who, "", e.into()),
+        };
+        let Some(name_of_file) = field.file_name().map(|s| s.to_string()) else {
+            continue;
+        };
+        if name_of_file.is_empty() {
+            continue;
+        }
+        let key = match guest_key(&dropbox.folder, &name_of_file) {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                println!("WARNING: Bad drop box file name blocked: {}", name_of_file);
+                return upload_failed(&who, &name_of_file, UploadError::BadRequest("Invalid filename"));
+            }
+            Err(e) => return upload_failed(&who, &name_of_file, e.into()),
+        };
+
+        //a Content-Length tells us the most this file can be, so the rest of the box stays free for other guests.
+        let wanted = match total_request_size {
+            0 => u64::MAX,
+            size => size.saturating_sub(global_written),
+        };
+        let Some(slot) = DROPBOXES.reserve_file(&token, wanted) else {
+            return (StatusCode::FORBIDDEN, "This link is full").into_response();
+        };
+
+        match write_field(&who, Some(&dropbox.created_by), &mut field, &key, &name_of_file, total_request_size, &mut global_written, slot.limit, &limiter, expected.as_deref()).await {
+            Ok((bytes, stored_at)) => {
+                slot.finish(Some(bytes));
+                expiry::EXPIRY.set(&stored_at, None);
+                let stored_as = stored_at.rsplit('/').next().unwrap_or(&name_of_file).to_string();
+                println!("\n   📥 {} dropped '{}' into '{}' as '{}' on {}", addr.ip(), name_of_file, dropbox.folder, stored_as, get_time());
+                received.push(name_of_file);
+            }
+            Err(e) => {
+                slot.finish(None);
+                return upload_failed(&who, &name_of_file, e);
+            }
+        }
+    }
+
+    (StatusCode::OK, format!("Thanks! {} file(s) received: {}", received.len(), received.join(", "))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_names_are_one_plain_name() {
+        assert_eq!(guest_name("photo.jpg").as_deref(), Some("photo.jpg"));
+        assert_eq!(guest_name(".env").as_deref(), Some(".env"));
+        for bad in ["", "..", "a/b.jpg", "../a.jpg", "a\\b.jpg", "a\0.jpg", ".tus", ".trash", ".versions", ".blobs", ".x.rshare-part"] {
+            assert!(guest_name(bad).is_none(), "{:?} got through", bad);
+        }
+    }
+
+    #[test]
+    fn guest_keys_never_land_on_rshares_own_names() {
+        let key = guest_key("box-1", "photo.jpg").unwrap().unwrap();
+        let stored_as = key.strip_prefix("box-1/").unwrap();
+        assert!(stored_as.starts_with("photo-") && stored_as.ends_with(".jpg"));
+        assert_eq!(stored_as.len(), "photo-.jpg".len() + 12);
+        assert!(guest_key("box-1", ".x.rshare-part").unwrap().is_none());
+        assert!(guest_key("box-1", "../photo.jpg").unwrap().is_none());
+    }
+}
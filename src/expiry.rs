@@ -0,0 +1,207 @@
+//Files that delete themselves. An uploader can pick when a file expires from `expiry_choices` in config.ini,
+//and anything without a pick goes after `file_retention`. Picks are kept in expiry.json, the default goes
+//by when the upload finished, from owners.json. The cleanup ticker deletes expired files for good, not into the trash.
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Mutex,
+};
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{CONFIG, files, get_time, parse_math_string, quota, storage, upload_error::UploadError};
+
+const EXPIRY_PATH: &str = "expiry.json";
+
+///When each file with a picked expiry goes, in unix seconds. None is a pick of never.
+pub struct ExpiryStore {
+    files: Mutex<HashMap<String, Option<i64>>>,
+}
+
+pub static EXPIRY: Lazy<ExpiryStore> = Lazy::new(|| {
+    let files: HashMap<String, Option<i64>> = fs::read_to_string(EXPIRY_PATH)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    ExpiryStore { files: Mutex::new(files) }
+});
+
+impl ExpiryStore {
+    fn save(files: &HashMap<String, Option<i64>>) {
+        match serde_json::to_string_pretty(files) {
+            Ok(json) => {
+                if let Err(e) = fs::write(EXPIRY_PATH, json) {
+                    println!("ERROR! Could not save {}: {}", EXPIRY_PATH, e);
+                }
+            }
+            Err(e) => println!("ERROR! Could not save {}: {}", EXPIRY_PATH, e),
+        }
+    }
+
+    ///Sets when a freshly uploaded file expires.
+    ///
+    /// * `expires_in` - what the uploader picked, from pick(). None means the default, Some(0) never.
+    pub fn set(&self, key: &str, expires_in: Option<u64>) {
+        let mut files = self.files.lock().unwrap();
+        let changed = match expires_in {
+            //a new upload under an old name doesn't keep the old file's pick.
+            None => files.remove(key).is_some(),
+            Some(0) => files.insert(key.to_string(), None) != Some(None),
+            Some(secs) => {
+                files.insert(key.to_string(), Some(chrono::Utc::now().timestamp().saturating_add(secs as i64)));
+                true
+            }
+        };
+        if changed {
+            Self::save(&files);
+        }
+    }
+
+    ///When a file expires, if it does.
+    ///
+    /// * `modified` - its modified time in unix seconds. The default only goes by it for files uploaded before
+    ///   rShare kept the upload time.
+    pub fn expires_at(&self, key: &str, modified: i64) -> Option<i64> {
+        if let Some(picked) = self.files.lock().unwrap().get(key) {
+            return *picked;
+        }
+        let uploaded = quota::OWNERS.uploaded_at(key).unwrap_or(modified);
+        (CONFIG.file_retention_secs > 0).then(|| uploaded.saturating_add(CONFIG.file_retention_secs as i64))
+    }
+
+    ///Keeps the picks with a file or folder when it gets renamed or moved.
+    pub fn moved(&self, from: &str, to: &str) {
+        let mut files = self.files.lock().unwrap();
+        if files::rekey(&mut files, from, to) {
+            Self::save(&files);
+        }
+    }
+
+    ///Forgets a file or folder that was deleted for good.
+    pub fn removed(&self, key: &str) {
+        let mut files = self.files.lock().unwrap();
+        let before = files.len();
+        files.retain(|k, _| !Path::new(k).starts_with(key));
+        if files.len() < before {
+            Self::save(&files);
+        }
+    }
+}
+
+///Checks what an uploader asked for against expiry_choices.
+///
+/// * `text` - seconds from the form or query, or "" / "default" for file_retention
+/// * Returns None for the default, Some(0) for never, or Some(seconds). BadRequest if it isn't one of the choices.
+pub fn pick(text: &str) -> Result<Option<u64>, UploadError> {
+    let text = text.trim();
+    if text.is_empty() || text.eq_ignore_ascii_case("default") {
+        return Ok(None);
+    }
+    let secs = if text.eq_ignore_ascii_case("never") { Some(0) } else { text.parse::<u64>().ok() };
+    match secs.filter(|secs| CONFIG.expiry_choices.contains(secs)) {
+        Some(secs) => Ok(Some(secs)),
+        None => Err(UploadError::BadRequest("That expiry isn't one of the choices")),
+    }
+}
+
+///Reads the expiry_choices line, like `60*60, 24*60*60, never`. Bad entries get skipped with a warning.
+pub fn parse_choices(text: &str) -> Vec<u64> {
+    let mut choices = vec![];
+    for choice in text.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+        if choice.eq_ignore_ascii_case("never") {
+            choices.push(0);
+        } else {
+            //u64::MAX is never a sensible expiry, so it can stand for "couldn't read it".
+            match parse_math_string(choice, u64::MAX) {
+                u64::MAX => println!("Warning: Could not read the expiry choice '{}'. Skipping it.", choice),
+                secs => choices.push(secs),
+            }
+        }
+    }
+    choices
+}
+
+///Like `7 days` or `90 minutes`, for the dashboard.
+fn describe(secs: u64) -> String {
+    if secs == 0 {
+        return "never".to_string();
+    }
+    let units = [(7 * 24 * 60 * 60, "week"), (24 * 60 * 60, "day"), (60 * 60, "hour"), (60, "minute"), (1, "second")];
+    let (size, unit) = units.into_iter().find(|(size, _)| secs.is_multiple_of(*size)).unwrap_or((1, "second"));
+    let n = secs / size;
+    format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" })
+}
+
+///One of the expiries an uploader can pick. `secs` of 0 is never.
+#[derive(Serialize)]
+pub struct Choice {
+    secs: u64,
+    label: String,
+}
+
+///What GET /expiry sends back. `default` is file_retention in seconds, None if files are kept until deleted.
+#[derive(Serialize)]
+pub struct ExpiryOptions {
+    default: Option<u64>,
+    default_label: String,
+    choices: Vec<Choice>,
+}
+
+///GET /expiry. The choices for the upload form.
+pub async fn expiry_options() -> Response {
+    let default = Some(CONFIG.file_retention_secs).filter(|secs| *secs > 0);
+    let choices = CONFIG.expiry_choices.iter().map(|secs| Choice { secs: *secs, label: describe(*secs) }).collect();
+    Json(ExpiryOptions { default, default_label: describe(default.unwrap_or(0)), choices }).into_response()
+}
+
+///Every file in storage with its modified time. rShare's own folders never expire.
+async fn every_file() -> Vec<(String, i64)> {
+    let mut found = vec![];
+    let mut folders = vec![String::new()];
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = storage::STORAGE.list(&folder).await else { continue };
+        for entry in entries.into_iter().filter(|e| !files::is_own_name(&e.name)) {
+            let key = if folder.is_empty() { entry.name } else { format!("{}/{}", folder, entry.name) };
+            match entry.is_dir {
+                true => folders.push(key),
+                false => found.push((key, entry.modified)),
+            }
+        }
+    }
+    found
+}
+
+///Deletes everything past its expiry. Called from the cleanup ticker.
+pub async fn purge_expired() {
+    let now = chrono::Utc::now().timestamp();
+    //with no default, only files with a pick can expire, so there's no need to look through everything.
+    let candidates: Vec<(String, i64)> = match CONFIG.file_retention_secs {
+        //picks for things in the trash wait there. the trash has its own retention.
+        0 => EXPIRY
+            .files
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| files::clean_path(key).is_some())
+            .filter_map(|(key, at)| at.map(|at| (key.clone(), at)))
+            .collect(),
+        _ => every_file().await.into_iter().filter_map(|(key, modified)| EXPIRY.expires_at(&key, modified).map(|at| (key, at))).collect(),
+    };
+    for (key, _) in candidates.into_iter().filter(|(_, at)| now >= *at) {
+        match storage::STORAGE.delete(&key).await {
+            Ok(()) => println!("⌛ '{}' expired and was deleted on {}", key, get_time()),
+            //already gone some other way. nothing to do but forget it.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                println!("ERROR! Could not delete expired '{}': {}", key, e);
+                continue;
+            }
+        }
+        files::forget_records(&key);
+    }
+}
@@ -0,0 +1,259 @@
+//Cleaning up uploads without going to the server: delete (into the trash), rename and move, one at a time or in bulk.
+//Paths go through the same checks as downloads, and every change is logged with who and when.
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+use axum::{
+    extract::{Extension, Path as UrlPath},
+    http::{StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{files, get_time, quota, storage, trash, users};
+
+///Why a file operation didn't happen.
+///
+/// * `BadPath` - a traversal attempt, the uploads folder itself, or a folder into itself. 400.
+/// * `NotFound` - nothing there, or nothing this user is allowed to see. 404.
+/// * `NotYours` - someone else uploaded it, and only they or an admin can change it. 403.
+/// * `Exists` - something already has the new name. 409.
+/// * `Io` - the disk said no. 500.
+#[derive(Debug)]
+pub enum FileOpError {
+    BadPath,
+    NotFound,
+    NotYours,
+    Exists,
+    Io(std::io::Error),
+}
+
+impl FileOpError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FileOpError::BadPath => StatusCode::BAD_REQUEST,
+            FileOpError::NotFound => StatusCode::NOT_FOUND,
+            FileOpError::NotYours => StatusCode::FORBIDDEN,
+            FileOpError::Exists => StatusCode::CONFLICT,
+            FileOpError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    ///What the browser gets told. Disk details stay in the log.
+    fn public_message(&self) -> String {
+        match self {
+            FileOpError::Io(_) => "Could not change the file".to_string(),
+            other => other.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for FileOpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileOpError::BadPath => write!(f, "Invalid path"),
+            FileOpError::NotFound => write!(f, "File not found"),
+            FileOpError::NotYours => write!(f, "Only whoever uploaded this, or an admin, can change it"),
+            FileOpError::Exists => write!(f, "Something with that name is already there"),
+            FileOpError::Io(e) => write!(f, "Disk error: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for FileOpError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => FileOpError::NotFound,
+            _ => FileOpError::Io(e),
+        }
+    }
+}
+
+impl IntoResponse for FileOpError {
+    fn into_response(self) -> Response {
+        (self.status(), self.public_message()).into_response()
+    }
+}
+
+///Finds something the user wants to change. Never the uploads folder itself, and only what they may change.
+///
+/// * Returns the cleaned relative path and the real path on disk.
+fn existing(path: &str, user: &users::AuthUser) -> Result<(PathBuf, PathBuf), FileOpError> {
+    let rel = files::clean_path(path).filter(|rel| rel.file_name().is_some()).ok_or(FileOpError::BadPath)?;
+    let full = files::resolve(path, user).ok_or(FileOpError::NotFound)?;
+    if !quota::OWNERS.may_change(&files::display_path(&rel), user, full.is_dir()) {
+        return Err(FileOpError::NotYours);
+    }
+    Ok((rel, full))
+}
+
+///Deletes a file, or a folder with everything in it. It goes to the trash, so it can still come back.
+async fn delete_path(path: &str, user: &users::AuthUser) -> Result<(), FileOpError> {
+    //the trash is a folder in uploads. other storage deletes for good.
+    if !storage::is_local() {
+        let rel = files::clean_path(path).filter(|rel| rel.file_name().is_some()).ok_or(FileOpError::BadPath)?;
+        if !files::may_see(&rel, user) {
+            return Err(FileOpError::NotFound);
+        }
+        //folders there are only names in front of files, so there's no empty one to let through.
+        if !quota::OWNERS.may_change(&files::display_path(&rel), user, false) {
+            return Err(FileOpError::NotYours);
+        }
+        let key = files::display_path(&rel);
+        storage::STORAGE.delete(&key).await?;
+        files::forget_records(&key);
+        println!("🗑️ '{}' deleted '{}' for good on {}", user.name, key, get_time());
+        return Ok(());
+    }
+    let (rel, full) = existing(path, user)?;
+    trash::TRASH.put(&rel, &full, user).await?;
+    println!("🗑️ '{}' deleted '{}' on {}", user.name, files::display_path(&rel), get_time());
+    Ok(())
+}
+
+///Renames a file or folder where it is.
+///
+/// * `new_name` - just the name, no folders
+/// * Returns the new path.
+async fn rename_path(path: &str, new_name: &str, user: &users::AuthUser) -> Result<String, FileOpError> {
+    let (rel, full) = existing(path, user)?;
+    let name = files::clean_path(new_name).filter(|n| n.components().count() == 1).ok_or(FileOpError::BadPath)?;
+    put(&full, &full.with_file_name(&name)).await?;
+    let old_path = files::display_path(&rel);
+    let new_path = files::display_path(&rel.with_file_name(&name));
+    files::move_records(&old_path, &new_path);
+    println!("✏️ '{}' renamed '{}' to '{}' on {}", user.name, old_path, new_path, get_time());
+    Ok(new_path)
+}
+
+///Moves a file or folder into another folder, keeping its name.
+///
+/// * `to` - the folder, "" for the top of uploads
+/// * Returns the new path.
+async fn move_path(path: &str, to: &str, user: &users::AuthUser) -> Result<String, FileOpError> {
+    let (rel, full) = existing(path, user)?;
+    let to_rel = files::clean_path(to).ok_or(FileOpError::BadPath)?;
+    let folder = files::resolve(to, user).filter(|p| p.is_dir()).ok_or(FileOpError::NotFound)?;
+    //a folder can't go inside itself.
+    if folder.starts_with(&full) {
+        return Err(FileOpError::BadPath);
+    }
+    let name = full.file_name().ok_or(FileOpError::BadPath)?;
+    put(&full, &folder.join(name)).await?;
+    let old_path = files::display_path(&rel);
+    let new_path = files::display_path(&to_rel.join(name));
+    files::move_records(&old_path, &new_path);
+    println!("📦 '{}' moved '{}' to '{}' on {}", user.name, old_path, new_path, get_time());
+    Ok(new_path)
+}
+
+///Renames `from` to `to` without ever replacing what is already at `to`.
+async fn put(from: &Path, to: &Path) -> Result<(), FileOpError> {
+    if tokio::fs::symlink_metadata(to).await.is_ok() {
+        return Err(FileOpError::Exists);
+    }
+    tokio::fs::rename(from, to).await?;
+    Ok(())
+}
+
+///Logs a failed operation and hands back the error.
+fn failed(user: &users::AuthUser, action: &str, path: &str, error: FileOpError) -> FileOpError {
+    println!("❌ '{}' could not {} '{}' on {} ({}): {}", user.name, action, path, get_time(), error.status().as_u16(), error);
+    error
+}
+
+///DELETE /files/{*path}
+pub async fn delete_file(Extension(user): Extension<users::AuthUser>, UrlPath(path): UrlPath<String>) -> Response {
+    match delete_path(&path, &user).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => failed(&user, "delete", &path, e).into_response(),
+    }
+}
+
+///DELETE /files/rename, /files/move and /files/bulk. The POSTs own those routes, so a file at the top of uploads
+///with one of those names gets deleted through here instead.
+pub async fn delete_taken_name(user: Extension<users::AuthUser>, uri: Uri) -> Response {
+    let name = uri.path().rsplit('/').next().unwrap_or_default().to_string();
+    delete_file(user, UrlPath(name)).await
+}
+
+#[derive(Deserialize)]
+pub struct RenameRequest {
+    path: String,
+    new_name: String,
+}
+
+///POST /files/rename with `{"path": "docs/a.txt", "new_name": "b.txt"}`
+pub async fn rename_file(Extension(user): Extension<users::AuthUser>, Json(request): Json<RenameRequest>) -> Response {
+    match rename_path(&request.path, &request.new_name, &user).await {
+        Ok(new_path) => Json(new_path).into_response(),
+        Err(e) => failed(&user, "rename", &request.path, e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MoveRequest {
+    path: String,
+    to: String,
+}
+
+///POST /files/move with `{"path": "a.txt", "to": "docs"}`
+pub async fn move_file(Extension(user): Extension<users::AuthUser>, Json(request): Json<MoveRequest>) -> Response {
+    match move_path(&request.path, &request.to, &user).await {
+        Ok(new_path) => Json(new_path).into_response(),
+        Err(e) => failed(&user, "move", &request.path, e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkAction {
+    Delete,
+    Move,
+}
+
+///The body for POST /files/bulk.
+///
+/// * `to` - the folder for a move. Not needed for delete.
+#[derive(Deserialize)]
+pub struct BulkRequest {
+    action: BulkAction,
+    paths: Vec<String>,
+    to: Option<String>,
+}
+
+///How one path in a bulk request went.
+///
+/// * `new_path` - where it ended up after a move
+/// * `error` - why it didn't happen. Everything else in the list still gets a go.
+#[derive(Serialize)]
+pub struct BulkResult {
+    path: String,
+    ok: bool,
+    new_path: Option<String>,
+    error: Option<String>,
+}
+
+///POST /files/bulk. Deletes or moves a list of paths, one by one.
+///
+/// * Always 200. Look at each result to see what worked.
+pub async fn bulk(Extension(user): Extension<users::AuthUser>, Json(request): Json<BulkRequest>) -> Response {
+    if matches!(request.action, BulkAction::Move) && request.to.is_none() {
+        return (StatusCode::BAD_REQUEST, "A move needs a folder in \"to\"").into_response();
+    }
+    let mut results = Vec::with_capacity(request.paths.len());
+    for path in request.paths {
+        let outcome = match (&request.action, &request.to) {
+            (BulkAction::Delete, _) => delete_path(&path, &user).await.map(|()| None).map_err(|e| failed(&user, "delete", &path, e)),
+            (BulkAction::Move, Some(to)) => move_path(&path, to, &user).await.map(Some).map_err(|e| failed(&user, "move", &path, e)),
+            (BulkAction::Move, None) => Err(FileOpError::BadPath),
+        };
+        results.push(match outcome {
+            Ok(new_path) => BulkResult { path, ok: true, new_path, error: None },
+            Err(e) => BulkResult { path, ok: false, new_path: None, error: Some(e.public_message()) },
+        });
+    }
+    Json(results).into_response()
+}
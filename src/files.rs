@@ -0,0 +1,470 @@
+//The file list for the dashboard and for scripts. Sizes, dates, types, and a checksum if you ask for it.
+//Also where paths from the browser get checked, so nothing can reach outside the uploads folder.
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+use axum::{
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{checksums, dropbox, expiry, format_time, get_time, quota, shares, staging, storage, users, versions};
+
+///Where everything lives.
+pub const UPLOADS_DIR: &str = "uploads";
+
+///rShare's own folders in uploads: resumable uploads, the trash, old versions and dedup's copies.
+const OWN_FOLDERS: [&str; 4] = [".tus", ".trash", ".versions", ".blobs"];
+
+///Whether a name in uploads is rShare's rather than someone's file. Those never show up or get reached through a path.
+///
+/// * Other names starting with a dot, like `.env`, are files like any other.
+pub fn is_own_name(name: &str) -> bool {
+    OWN_FOLDERS.contains(&name) || staging::is_staging_name(name)
+}
+
+///Checks a path from the browser, like `photos/2024/beach.jpg`, and turns it into one relative to uploads.
+///
+/// * Extra slashes are fine. `.`, `..`, rShare's own names (like .tus), backslashes and NULs are not.
+/// * "" gives back an empty path, which means uploads itself.
+/// * Returns None if anything looks off. Nothing here touches the disk.
+pub fn clean_path(path: &str) -> Option<PathBuf> {
+    let mut clean = PathBuf::new();
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        if part == "." || part == ".." || is_own_name(part) || part.contains('\\') || part.contains('\0') {
+            return None;
+        }
+        clean.push(part);
+    }
+    //belt and braces. after the checks above this only ever sees plain names.
+    clean.components().all(|c| matches!(c, Component::Normal(_))).then_some(clean)
+}
+
+///Turns a relative path back into the `a/b/c.txt` form the browser uses.
+pub fn display_path(rel: &Path) -> String {
+    rel.components().filter_map(|c| c.as_os_str().to_str()).collect::<Vec<_>>().join("/")
+}
+
+///Turns a path on disk back into its path from the top of uploads, like `docs/a.txt`.
+///
+/// * Takes `uploads/docs/a.txt` as well as the real path inside_uploads gives back. None for anything outside.
+pub fn uploads_key(path: &Path) -> Option<String> {
+    let rel = match path.strip_prefix(UPLOADS_DIR) {
+        Ok(rel) => rel,
+        Err(_) => path.strip_prefix(Path::new(UPLOADS_DIR).canonicalize().ok()?).ok()?,
+    };
+    Some(display_path(rel))
+}
+
+///Moves every key at or under `from` over to `to`, for the stores keyed by path when something is renamed or moved.
+///
+/// * Returns true if anything changed, so the store knows to save.
+pub fn rekey<V>(map: &mut HashMap<String, V>, from: &str, to: &str) -> bool {
+    let keys: Vec<String> = map.keys().filter(|k| Path::new(k).starts_with(from)).cloned().collect();
+    for old in &keys {
+        if let Some(value) = map.remove(old) {
+            let rest = Path::new(old).strip_prefix(from).map(Path::to_path_buf).unwrap_or_default();
+            map.insert(display_path(&Path::new(to).join(rest)), value);
+        }
+    }
+    !keys.is_empty()
+}
+
+///Moves everything rShare keeps about a file or folder from one key to another: versions, owner, expiry pick,
+///checksums and share links. For renames and moves, and for trips into the trash and back.
+///
+/// * The owner comes along into the trash too, so trashed bytes still count against the uploader's quota.
+/// * Share links follow into the trash as well. They don't work while there, and work again on restore.
+pub fn move_records(from: &str, to: &str) {
+    versions::VERSIONS.moved(from, to);
+    quota::OWNERS.moved(from, to);
+    expiry::EXPIRY.moved(from, to);
+    checksums::CHECKSUMS.moved(from, to);
+    shares::SHARES.moved(from, to);
+}
+
+///Forgets everything rShare keeps about a file or folder that's gone for good, old copies included.
+pub fn forget_records(key: &str) {
+    versions::VERSIONS.removed(key);
+    quota::OWNERS.removed(key);
+    expiry::EXPIRY.removed(key);
+    checksums::CHECKSUMS.removed(key);
+    shares::SHARES.removed(key);
+}
+
+///Makes sure a path on disk really is inside uploads once symlinks are followed.
+///
+/// * Returns the real path if it is, None if it isn't or doesn't exist.
+pub fn inside_uploads(path: &Path) -> Option<PathBuf> {
+    let root = Path::new(UPLOADS_DIR).canonicalize().ok()?;
+    let full = path.canonicalize().ok()?;
+    full.starts_with(&root).then_some(full)
+}
+
+///Drop box folders are the admin's. Everyone else gets a 404 for anything in them.
+pub fn may_see(rel: &Path, user: &users::AuthUser) -> bool {
+    if user.role >= users::Role::Admin {
+        return true;
+    }
+    let first = rel.components().next().and_then(|c| c.as_os_str().to_str());
+    first.is_none_or(|folder| !dropbox::DROPBOXES.owns_folder(folder))
+}
+
+///Finds something that already exists under uploads.
+///
+/// * `path` - from the browser, like `photos/beach.jpg`
+/// * Returns the real path on disk, or None if it's missing, not allowed, or outside uploads.
+pub fn resolve(path: &str, user: &users::AuthUser) -> Option<PathBuf> {
+    let rel = clean_path(path)?;
+    if !may_see(&rel, user) {
+        return None;
+    }
+    inside_uploads(&Path::new(UPLOADS_DIR).join(rel))
+}
+
+///Works out where an upload goes and makes any folders it needs on the way.
+///
+/// * `folder` - the folder the upload was sent to, "" for the top
+/// * `file_name` - what the browser called it. Folder uploads send `trip/day1/a.jpg` here, which keeps their layout.
+/// * Returns None for bad names, or if the folder would end up outside uploads.
+pub async fn upload_destination(folder: &str, file_name: &str, user: &users::AuthUser) -> Option<PathBuf> {
+    key_destination(&upload_key(folder, file_name, user)?).await
+}
+
+///Where a key from upload_key goes on disk, with the folders it needs made. For when the key was checked earlier.
+pub async fn key_destination(key: &str) -> Option<PathBuf> {
+    let path = Path::new(UPLOADS_DIR).join(key);
+    make_folder(path.parent()?).await?;
+    Some(path)
+}
+
+///The storage key an upload goes under, like `trip/day1/a.jpg`. upload_destination without touching the disk.
+///
+/// * Returns None for bad names, and for drop box folders unless `user` is an admin.
+pub fn upload_key(folder: &str, file_name: &str, user: &users::AuthUser) -> Option<String> {
+    //an empty name would make the key the folder itself.
+    let name = clean_path(file_name).filter(|name| name.file_name().is_some())?;
+    let rel = clean_path(folder)?.join(name);
+    if !may_see(&rel, user) {
+        return None;
+    }
+    Some(display_path(&rel))
+}
+
+///Makes a folder under uploads, and any missing ones above it.
+///
+/// * Returns None if it can't be made or would end up outside uploads.
+pub async fn make_folder(path: &Path) -> Option<PathBuf> {
+    //check the part that already exists before making anything, so a symlink can't get folders made elsewhere.
+    existing_folder(path)?;
+    tokio::fs::create_dir_all(path).await.ok()?;
+    inside_uploads(path)
+}
+
+///The deepest part of `path` that already exists, as a real path. None if that's outside uploads.
+pub fn existing_folder(path: &Path) -> Option<PathBuf> {
+    inside_uploads(path.ancestors().find(|p| p.exists())?)
+}
+
+///One file or folder in the list.
+///
+/// * `path` - from the top of uploads, for downloads and for listing a folder
+/// * `modified` - when it was uploaded, or its modified time if it came from before rShare kept track. Unix seconds,
+///   for scripts. `modified_at` is the same thing for people.
+/// * `sha256` / `blake3` - from the checksums worked out on upload. Files without one yet get hashed with `hash=true`.
+/// * `versions` - how many old copies GET /versions has for it
+/// * `expires` - unix seconds when it gets deleted, None if it doesn't. `expires_at` is the same thing for people.
+#[derive(Serialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: i64,
+    pub modified_at: String,
+    pub mime: String,
+    pub sha256: Option<String>,
+    pub blake3: Option<String>,
+    pub versions: usize,
+    pub expires: Option<i64>,
+    pub expires_at: Option<String>,
+}
+
+///What `/files` sends back. `total` counts everything that matched, not just this page.
+#[derive(Serialize)]
+pub struct FileList {
+    pub path: String,
+    pub total: usize,
+    pub files: Vec<FileInfo>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    #[default]
+    Name,
+    Size,
+    Modified,
+    Type,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+///The query string for `/files`. Everything is optional.
+///
+/// * `path` - the folder to list. The top of uploads if left out.
+/// * `sort` / `order` - name, size, modified or type, asc or desc. Defaults to name, A to Z.
+/// * `ext` - only these extensions, comma separated, like `pdf,jpg`
+/// * `q` - only names containing this, any case
+/// * `offset` / `limit` - the page. No limit means everything.
+/// * `hash` - add the SHA-256 of each file on the page. Slow the first time for big files.
+#[derive(Deserialize, Default)]
+pub struct ListQuery {
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    sort: SortBy,
+    #[serde(default)]
+    order: SortOrder,
+    ext: Option<String>,
+    q: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    #[serde(default)]
+    hash: bool,
+}
+
+///list the files in one folder
+///
+/// * folders come first, then files. rShare's own, like .tus, never show up.
+/// * drop box folders only show up for admins.
+pub async fn list_files(Extension(user): Extension<users::AuthUser>, Query(query): Query<ListQuery>) -> Response {
+    let Some(folder_rel) = clean_path(&query.path).filter(|rel| may_see(rel, &user)) else {
+        return (StatusCode::NOT_FOUND, "Folder not found").into_response();
+    };
+    let Ok(entries) = storage::STORAGE.list(&display_path(&folder_rel)).await else {
+        return (StatusCode::NOT_FOUND, "Folder not found").into_response();
+    };
+
+    let mut files = vec![];
+    for entry in entries {
+        let rel = folder_rel.join(&entry.name);
+        if is_own_name(&entry.name) || !may_see(&rel, &user) || !matches_filters(&entry.name, &query) {
+            continue;
+        }
+        let mime = match entry.is_dir {
+            true => "inode/directory".to_string(),
+            false => mime_guess::from_path(&entry.name).first_or_octet_stream().to_string(),
+        };
+        let path = display_path(&rel);
+        let expires = if entry.is_dir { None } else { expiry::EXPIRY.expires_at(&path, entry.modified) };
+        //dedup hands an upload the modified time of the first copy of its bytes. when it came in is what people want.
+        let modified = if entry.is_dir { entry.modified } else { quota::OWNERS.uploaded_at(&path).unwrap_or(entry.modified) };
+        files.push(FileInfo {
+            name: entry.name,
+            versions: if entry.is_dir { 0 } else { versions::count(&path) },
+            path,
+            is_dir: entry.is_dir,
+            size: entry.size,
+            modified,
+            modified_at: format_time(modified),
+            mime,
+            sha256: None,
+            blake3: None,
+            expires,
+            expires_at: expires.map(format_time),
+        });
+    }
+
+    files.sort_by(|a, b| {
+        //folders on top no matter the order, like every file manager.
+        let folders_first = b.is_dir.cmp(&a.is_dir);
+        let by = match query.sort {
+            SortBy::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortBy::Size => a.size.cmp(&b.size),
+            SortBy::Modified => a.modified.cmp(&b.modified),
+            SortBy::Type => a.mime.cmp(&b.mime),
+        };
+        //name breaks ties so pages don't shuffle between requests.
+        let by = by.then_with(|| a.name.cmp(&b.name));
+        folders_first.then(if query.order == SortOrder::Desc { by.reverse() } else { by })
+    });
+
+    let total = files.len();
+    let mut files: Vec<FileInfo> = files.into_iter().skip(query.offset).take(query.limit.unwrap_or(usize::MAX)).collect();
+
+    //only the page that goes out, and off the async threads since hash=true can read whole files.
+    let hash = query.hash;
+    files = tokio::task::spawn_blocking(move || {
+        for file in files.iter_mut().filter(|f| !f.is_dir) {
+            //checksums are kept for files on disk only.
+            let Some(path) = storage::STORAGE.local_path(&file.path) else { continue };
+            let checksum = match hash {
+                true => checksums::CHECKSUMS.get_or_hash(&path),
+                false => checksums::CHECKSUMS.known(&path),
+            };
+            if let Some(checksum) = checksum {
+                file.sha256 = Some(checksum.sha256);
+                file.blake3 = checksum.blake3;
+            }
+        }
+        files
+    })
+    .await
+    .unwrap_or_default();
+
+    Json(FileList { path: display_path(&folder_rel), total, files }).into_response()
+}
+
+///`?path=` and `?expires_in=`, for the upload form.
+///
+/// * `expires_in` - seconds from expiry_choices, "never", or "" for file_retention
+#[derive(Deserialize)]
+pub struct FolderQuery {
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub expires_in: String,
+}
+
+///Percent encodes a path for a redirect. Slashes stay as they are.
+pub fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct CreateFolder {
+    path: String,
+}
+
+///Makes a folder, and any missing ones above it. Already existing is fine too.
+pub async fn create_folder(Extension(user): Extension<users::AuthUser>, Json(request): Json<CreateFolder>) -> Response {
+    let Some(rel) = clean_path(&request.path).filter(|rel| rel.file_name().is_some() && may_see(rel, &user)) else {
+        println!("WARNING: Bad folder name blocked: {}", request.path);
+        return (StatusCode::BAD_REQUEST, "Invalid folder name").into_response();
+    };
+    let path = Path::new(UPLOADS_DIR).join(&rel);
+    if path.ancestors().any(Path::is_file) {
+        return (StatusCode::CONFLICT, "A file with that name is in the way").into_response();
+    }
+    if make_folder(&path).await.is_none() {
+        return (StatusCode::BAD_REQUEST, "Invalid folder name").into_response();
+    }
+    println!("📁 '{}' made the folder '{}' on {}", user.name, display_path(&rel), get_time());
+    (StatusCode::CREATED, Json(display_path(&rel))).into_response()
+}
+
+///Checks a name against the ext and q filters.
+fn matches_filters(name: &str, query: &ListQuery) -> bool {
+    let lower = name.to_lowercase();
+    if let Some(q) = query.q.as_deref().filter(|q| !q.is_empty())
+        && !lower.contains(&q.to_lowercase())
+    {
+        return false;
+    }
+    if let Some(ext) = query.ext.as_deref().filter(|e| !e.is_empty()) {
+        let file_ext = Path::new(&lower).extension().and_then(|e| e.to_str()).unwrap_or("");
+        return ext.split(',').any(|wanted| wanted.trim().trim_start_matches('.').to_lowercase() == file_ext);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(role: users::Role) -> users::AuthUser {
+        users::AuthUser { name: "bob".to_string(), role }
+    }
+
+    #[test]
+    fn clean_path_keeps_plain_paths() {
+        assert_eq!(clean_path("photos/2024/beach.jpg"), Some(PathBuf::from("photos/2024/beach.jpg")));
+        assert_eq!(clean_path("//photos///beach.jpg/"), Some(PathBuf::from("photos/beach.jpg")));
+        assert_eq!(clean_path(""), Some(PathBuf::new()));
+        //dots inside a name, and dotfiles that aren't rShare's, are just names.
+        assert_eq!(clean_path("a..b/.env"), Some(PathBuf::from("a..b/.env")));
+    }
+
+    #[test]
+    fn clean_path_stops_traversal() {
+        for bad in [
+            "..",
+            "../etc/passwd",
+            "photos/../../etc/passwd",
+            "photos/./beach.jpg",
+            "..\\etc\\passwd",
+            "photos\\beach.jpg",
+            "beach.jpg\0.txt",
+            "\0",
+        ] {
+            assert_eq!(clean_path(bad), None, "{:?} got through", bad);
+        }
+        //a leading slash is only an extra one. it can't get to the root of the disk.
+        assert_eq!(clean_path("/etc/passwd"), Some(PathBuf::from("etc/passwd")));
+    }
+
+    #[test]
+    fn clean_path_stops_rshares_own_names() {
+        for bad in [".tus", ".trash/abc", "docs/.versions", ".blobs/ab/cd", "docs/.a.txt.1a2b.rshare-part"] {
+            assert_eq!(clean_path(bad), None, "{:?} got through", bad);
+        }
+    }
+
+    #[test]
+    fn upload_key_joins_folder_and_name() {
+        let bob = user(users::Role::Admin);
+        assert_eq!(upload_key("", "a.txt", &bob).as_deref(), Some("a.txt"));
+        assert_eq!(upload_key("trip", "day1/a.jpg", &bob).as_deref(), Some("trip/day1/a.jpg"));
+        assert_eq!(upload_key("/trip/", "/a.jpg", &bob).as_deref(), Some("trip/a.jpg"));
+    }
+
+    #[test]
+    fn upload_key_turns_away_bad_names() {
+        let bob = user(users::Role::Uploader);
+        assert_eq!(upload_key("", "", &bob), None);
+        assert_eq!(upload_key("trip", "", &bob), None);
+        assert_eq!(upload_key("..", "a.txt", &bob), None);
+        assert_eq!(upload_key("trip", "../../a.txt", &bob), None);
+        assert_eq!(upload_key("trip", "..\\a.txt", &bob), None);
+        assert_eq!(upload_key("", ".tus", &bob), None);
+        assert_eq!(upload_key(".trash", "a.txt", &bob), None);
+    }
+
+    #[test]
+    fn rekey_moves_a_file_or_a_whole_folder() {
+        let mut map: HashMap<String, u32> = [("docs/a.txt", 1), ("docs/sub/b.txt", 2), ("docsy.txt", 3), ("other.txt", 4)]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect();
+
+        assert!(rekey(&mut map, "docs", "papers"));
+        assert_eq!(map.get("papers/a.txt"), Some(&1));
+        assert_eq!(map.get("papers/sub/b.txt"), Some(&2));
+        //only whole path parts match, so docsy.txt isn't in docs.
+        assert_eq!(map.get("docsy.txt"), Some(&3));
+        assert!(!map.contains_key("docs/a.txt"));
+
+        assert!(rekey(&mut map, "other.txt", "papers/other.txt"));
+        assert_eq!(map.get("papers/other.txt"), Some(&4));
+
+        assert!(!rekey(&mut map, "missing", "anywhere"));
+        assert_eq!(map.len(), 4);
+    }
+}
@@ -0,0 +1,150 @@
+//Anti brute force for /login. Counts failures per IP and across the whole server.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use once_cell::sync::Lazy;
+
+use crate::CONFIG;
+
+///How long the global counter looks back.
+const GLOBAL_WINDOW: Duration = Duration::from_secs(60);
+///How many failures from everyone inside the window before the whole login page locks.
+const GLOBAL_MAX_FAILURES: u32 = 100;
+///Forget about an IP once it has been quiet for this long.
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+///The failure history of one client address.
+///
+/// * `failures` - wrong passwords in a row
+/// * `last_failure` - when the last wrong password came in
+/// * `locked_until` - no login attempts are looked at before this
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+///Failures from everybody, so a botnet spreading guesses over many IPs still gets slowed down after its first guess from each.
+struct GlobalAttempts {
+    window_start: Instant,
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+pub struct LoginGuard {
+    per_ip: Mutex<HashMap<IpAddr, Attempts>>,
+    global: Mutex<GlobalAttempts>,
+}
+
+pub static LOGIN_GUARD: Lazy<LoginGuard> = Lazy::new(|| LoginGuard {
+    per_ip: Mutex::new(HashMap::new()),
+    global: Mutex::new(GlobalAttempts {
+        window_start: Instant::now(),
+        failures: 0,
+        locked_until: None,
+    }),
+});
+
+impl LoginGuard {
+    ///Checks if an IP is allowed to try a password right now, and counts the try if it is.
+    ///
+    /// * The try counts as a failure straight away, so a pile of requests at once can't all get past before
+    ///   the first wrong password is recorded. record_success wipes it again.
+    /// * The whole server lockout only stops IPs that have already got a password wrong, so nobody can
+    ///   lock everyone else out just by failing a lot.
+    /// * Returns `Err(wait)` with how long they have to wait if they are locked out.
+    pub fn begin(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut per_ip = self.per_ip.lock().unwrap();
+
+        let attempts = per_ip.get(&ip);
+        if let Some(until) = attempts.and_then(|a| a.locked_until)
+            && until > now
+        {
+            return Err(until - now);
+        }
+        if attempts.is_some_and(|a| a.failures > 0)
+            && let Some(until) = self.global.lock().unwrap().locked_until
+            && until > now
+        {
+            return Err(until - now);
+        }
+
+        let attempts = per_ip.entry(ip).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        attempts.failures += 1;
+        attempts.last_failure = now;
+
+        let free_tries = CONFIG.login_max_attempts as u32;
+        if attempts.failures >= free_tries {
+            //saturate so a really stubborn attacker can't overflow the shift.
+            let power = (attempts.failures - free_tries).min(30);
+            let lockout = Duration::from_secs((1u64 << power).min(CONFIG.login_max_lockout_secs));
+            attempts.locked_until = Some(now + lockout);
+        }
+        Ok(())
+    }
+
+    ///Records that the password from begin was wrong.
+    ///
+    /// * The lockout doubles every extra failure: 1s, 2s, 4s, 8s... up to `login_max_lockout` in the config.
+    /// * Returns how long the IP is now locked for, if at all.
+    pub fn record_failure(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        self.record_global_failure(now);
+
+        self.per_ip
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .and_then(|a| a.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    ///A correct password clears the history for that IP.
+    pub fn record_success(&self, ip: IpAddr) {
+        self.per_ip.lock().unwrap().remove(&ip);
+    }
+
+    ///Takes back the try begin counted, for a right password that shouldn't clear the rest, like a share link's.
+    ///
+    /// * begin only lets a try through when the IP isn't locked, so any lock there now came from this try. It goes too.
+    pub fn record_right_guess(&self, ip: IpAddr) {
+        if let Some(attempts) = self.per_ip.lock().unwrap().get_mut(&ip) {
+            attempts.failures = attempts.failures.saturating_sub(1);
+            attempts.locked_until = None;
+        }
+    }
+
+    ///Forgets IPs that haven't failed in a long time. Called from a background task in main.
+    pub fn purge_stale(&self) {
+        let now = Instant::now();
+        self.per_ip
+            .lock()
+            .unwrap()
+            .retain(|_, a| now.duration_since(a.last_failure) < FORGET_AFTER);
+    }
+
+    fn record_global_failure(&self, now: Instant) {
+        let mut global = self.global.lock().unwrap();
+        if now.duration_since(global.window_start) > GLOBAL_WINDOW {
+            global.window_start = now;
+            global.failures = 0;
+        }
+        global.failures += 1;
+
+        if global.failures >= GLOBAL_MAX_FAILURES {
+            println!("WARNING: Too many failed logins from everyone. Locking /login for IPs that got a password wrong for {}s.", GLOBAL_WINDOW.as_secs());
+            global.locked_until = Some(now + GLOBAL_WINDOW);
+            global.window_start = now;
+            global.failures = 0;
+        }
+    }
+}
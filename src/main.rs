@@ -1,7 +1,29 @@
 
+mod admin;
+mod checksums;
+mod dedup;
+mod dropbox;
+mod expiry;
+mod file_ops;
+mod files;
+mod lockout;
+mod password;
+mod quota;
+mod range;
+mod session;
+mod shares;
+mod staging;
+mod storage;
+mod throttle;
+mod trash;
+mod tus;
+mod upload_error;
+mod users;
+mod versions;
+
 use core::f64;
-use chrono;
 use std::{
+    collections::HashMap,
     env,
     fs,
     net::{SocketAddr, UdpSocket},
@@ -11,24 +33,19 @@ use std::{
     
 };//standard
 use axum_server::tls_rustls::RustlsConfig;
+use upload_error::UploadError;
 use once_cell::sync::Lazy;
-use mime_guess;
 use tower::ServiceBuilder;
-use tower_cookies::{Cookies, Cookie, CookieManagerLayer};
-use tokio::{
-    fs::File,
-    io::{AsyncWriteExt, BufReader,BufWriter},   // BufReader added for streaming  
-};
-use dotenvy;
+use tower_cookies::{Cookies, Cookie, CookieManagerLayer, cookie::SameSite};
 use tokio_util::io::ReaderStream; 
 use axum::{
     body::Body,
-    extract::{Multipart, Path, DefaultBodyLimit},
+    extract::{ConnectInfo, Extension, Multipart, Path, Query, DefaultBodyLimit, State, multipart::Field},
     http::{Request,header, HeaderMap, StatusCode,HeaderValue},
     middleware::Next,
     middleware,
     response::{Html, IntoResponse, Redirect,Response},
-    routing::{get, post},
+    routing::{delete, get, head, options, post},
     Json,
     Router,
     Form
@@ -39,6 +56,26 @@ pub struct AppConfig {
     pub max_upload_size: u64,
     pub upload_speed_bps: u64,   // 0 means unlimited
     pub download_speed_bps: u64, // 0 means unlimited
+    pub upload_speed_per_client_bps: u64,   // cap for one IP on top of the total, 0 means none
+    pub download_speed_per_client_bps: u64, // cap for one IP on top of the total, 0 means none
+    pub limit_rules: Vec<throttle::LimitRule>, // limit= lines, first match wins
+    pub trash_retention_secs: u64,  // deleted files are gone for good after this, 0 means keep them
+    pub file_retention_secs: u64,   // uploads get deleted this long after they came in, 0 means keep them
+    pub expiry_choices: Vec<u64>,   // the expiries an uploader can pick instead, 0 is never
+    pub on_collision: staging::Collision, // what happens when an upload's name is taken
+    pub max_versions: u64,          // old copies kept per file when an upload overwrites it, 0 means none
+    pub checksum_blake3: bool,      // work out a BLAKE3 next to the SHA-256 for every upload
+    pub dedup: bool,                // keep the same bytes only once, however many names they have
+    pub storage: storage::StorageKind, // where uploads are kept: local, memory or s3
+    pub s3: storage::S3Settings,    // the bucket, when storage is s3
+    pub quota_total: u64,           // the most everything in uploads together can take, 0 means no cap
+    pub quota_per_user: u64,        // the most one user's uploads can take, 0 means no cap
+    pub user_quotas: HashMap<String, u64>, // quota= lines, for users who get something else
+    pub min_free_space: u64,        // disk space uploads never eat into
+    pub session_idle_secs: u64,     // log out after this long without a request
+    pub session_lifetime_secs: u64, // log out after this long no matter what
+    pub login_max_attempts: u64,    // wrong passwords allowed before the lockout starts
+    pub login_max_lockout_secs: u64, // the longest a single lockout can get
 }
 
 //Let's create a config for users. 
@@ -50,6 +87,26 @@ static CONFIG: Lazy<AppConfig> = Lazy::new(|| {
         max_upload_size: 1024 * 1024 * 1024, // 1GB
         upload_speed_bps: 1024*1024,                 // 1 MB default
         download_speed_bps: 1024*1024,               // 1 MB default
+        upload_speed_per_client_bps: 0,
+        download_speed_per_client_bps: 0,
+        limit_rules: Vec::new(),
+        trash_retention_secs: 30*24*60*60,           // 30 days
+        file_retention_secs: 0,
+        expiry_choices: vec![60*60, 24*60*60, 7*24*60*60, 30*24*60*60, 0], // an hour, a day, a week, 30 days, never
+        on_collision: staging::Collision::Rename,
+        max_versions: 10,
+        checksum_blake3: false,
+        dedup: false,
+        storage: storage::StorageKind::Local,
+        s3: storage::S3Settings::default(),
+        quota_total: 0,
+        quota_per_user: 0,
+        user_quotas: HashMap::new(),
+        min_free_space: 100*1024*1024,               // 100 MB
+        session_idle_secs: 30*60,                    // 30 minutes
+        session_lifetime_secs: 12*60*60,             // 12 hours
+        login_max_attempts: 5,
+        login_max_lockout_secs: 15*60,               // 15 minutes
     };
 
     if !std::path::Path::new(config_path).exists() {
@@ -64,6 +121,50 @@ static CONFIG: Lazy<AppConfig> = Lazy::new(|| {
         writeln!(file, "file_Size= 1024*1024*1024").unwrap();
         writeln!(file, "upload_speed= 1024*1024").unwrap();
         writeln!(file, "download_speed= 1024*1024").unwrap();
+        writeln!(file, "# The speeds above are shared by everyone. These cap a single IP on top of that (0 = no extra cap).").unwrap();
+        writeln!(file, "upload_speed_per_client= 0").unwrap();
+        writeln!(file, "download_speed_per_client= 0").unwrap();
+        writeln!(file, "# Limits for one client, one per line, the first that matches wins: limit= <who> <setting>=<value> ...").unwrap();
+        writeln!(file, "# <who> is an IP, user:<name>, or guests (share and drop box links).").unwrap();
+        writeln!(file, "# Settings: uploads= / downloads= (how many at once, 0 = no cap), upload_speed= / download_speed= (bytes per second, 0 = no cap), or just unlimited.").unwrap();
+        writeln!(file, "#limit= guests downloads=2 download_speed=5*1024*1024").unwrap();
+        writeln!(file, "#limit= 192.168.1.50 unlimited").unwrap();
+        writeln!(file, "# Sessions in seconds. Idle logs you out after no activity, lifetime logs you out no matter what.").unwrap();
+        writeln!(file, "session_idle= 30*60").unwrap();
+        writeln!(file, "session_lifetime= 12*60*60").unwrap();
+        writeln!(file, "# Wrong passwords allowed per IP before lockouts start. Each extra miss doubles the lockout, up to the max in seconds.").unwrap();
+        writeln!(file, "login_attempts= 5").unwrap();
+        writeln!(file, "login_lockout= 15*60").unwrap();
+        writeln!(file, "# Deleted files wait in the trash this many seconds before they are gone for good (0 = until emptied by hand).").unwrap();
+        writeln!(file, "trash_retention= 30*24*60*60").unwrap();
+        writeln!(file, "# When an upload has the same name as a file already there: rename (report (1).pdf), overwrite, or reject (409).").unwrap();
+        writeln!(file, "on_collision= rename").unwrap();
+        writeln!(file, "# With on_collision= overwrite, the old copies kept per file so they can be downloaded or restored (0 = keep none, 10 if left out).").unwrap();
+        writeln!(file, "#max_versions= 10").unwrap();
+        writeln!(file, "# Every upload gets a SHA-256. Set this to on to work out a BLAKE3 too.").unwrap();
+        writeln!(file, "blake3= off").unwrap();
+        writeln!(file, "# Store files with the same contents only once, whatever they are called. Needs Linux or macOS.").unwrap();
+        writeln!(file, "dedup= off").unwrap();
+        writeln!(file, "# Where uploads are kept: local (the uploads folder), memory (gone on restart) or s3 (an S3 compatible object store).").unwrap();
+        writeln!(file, "# The trash, versions, dedup, checksums and rename/move need local.").unwrap();
+        writeln!(file, "storage= local").unwrap();
+        writeln!(file, "# For storage= s3. Path style, so MinIO and most NAS object stores work as they are.").unwrap();
+        writeln!(file, "#s3_endpoint= http://nas.local:9000").unwrap();
+        writeln!(file, "#s3_bucket= rshare").unwrap();
+        writeln!(file, "#s3_region= us-east-1").unwrap();
+        writeln!(file, "#s3_access_key= ").unwrap();
+        writeln!(file, "#s3_secret_key= ").unwrap();
+        writeln!(file, "# Quotas in bytes (0 = no cap). quota_total is for everything in uploads, quota_per_user for each user's own uploads.").unwrap();
+        writeln!(file, "quota_total= 0").unwrap();
+        writeln!(file, "quota_per_user= 0").unwrap();
+        writeln!(file, "# A different quota for one user: quota= <name> <bytes>").unwrap();
+        writeln!(file, "#quota= bob 10*1024*1024*1024").unwrap();
+        writeln!(file, "# Uploads stop with a 507 before the disk has less than this free.").unwrap();
+        writeln!(file, "min_free_space= 100*1024*1024").unwrap();
+        writeln!(file, "# Uploads get deleted this many seconds after they came in (0 = keep them). Gone for good, not into the trash.").unwrap();
+        writeln!(file, "file_retention= 0").unwrap();
+        writeln!(file, "# What an uploader can pick instead, in seconds. never keeps the file until someone deletes it.").unwrap();
+        writeln!(file, "expiry_choices= 60*60, 24*60*60, 7*24*60*60, 30*24*60*60, never").unwrap();
         
         return current_config;
     }
@@ -72,6 +173,8 @@ static CONFIG: Lazy<AppConfig> = Lazy::new(|| {
 
     // Read the file and update the struct if values are found
     let content = std::fs::read_to_string(config_path).unwrap_or_default();
+    //only someone who asked for versions needs telling they won't get any.
+    let mut max_versions_set = false;
     //for all the lines...
     for line in content.lines() {
         // Ignore lines that start with '#' (comments)
@@ -92,8 +195,98 @@ static CONFIG: Lazy<AppConfig> = Lazy::new(|| {
             //println!("eval download speed");
             current_config.download_speed_bps = parse_math_string(val, current_config.download_speed_bps);
             //println!("download speed {}",current_config.download_speed_bps);
+        } else if let Some(val) = line.strip_prefix("upload_speed_per_client=") {
+            current_config.upload_speed_per_client_bps = parse_math_string(val, current_config.upload_speed_per_client_bps);
+
+        } else if let Some(val) = line.strip_prefix("download_speed_per_client=") {
+            current_config.download_speed_per_client_bps = parse_math_string(val, current_config.download_speed_per_client_bps);
+
+        } else if let Some(val) = line.strip_prefix("limit=") {
+            match throttle::LimitRule::parse(val) {
+                Some(rule) => current_config.limit_rules.push(rule),
+                None => println!("Warning: Could not read the limit line '{}'. Skipping it.", line),
+            }
+
+        } else if let Some(val) = line.strip_prefix("session_idle=") {
+            current_config.session_idle_secs = parse_math_string(val, current_config.session_idle_secs);
+
+        } else if let Some(val) = line.strip_prefix("session_lifetime=") {
+            current_config.session_lifetime_secs = parse_math_string(val, current_config.session_lifetime_secs);
+
+        } else if let Some(val) = line.strip_prefix("login_attempts=") {
+            current_config.login_max_attempts = parse_math_string(val, current_config.login_max_attempts);
+
+        } else if let Some(val) = line.strip_prefix("login_lockout=") {
+            current_config.login_max_lockout_secs = parse_math_string(val, current_config.login_max_lockout_secs);
+
+        } else if let Some(val) = line.strip_prefix("trash_retention=") {
+            current_config.trash_retention_secs = parse_math_string(val, current_config.trash_retention_secs);
+
+        } else if let Some(val) = line.strip_prefix("on_collision=") {
+            match staging::Collision::parse(val) {
+                Some(policy) => current_config.on_collision = policy,
+                None => println!("Warning: on_collision should be rename, overwrite or reject. Keeping {:?}.", current_config.on_collision),
+            }
+
+        } else if let Some(val) = line.strip_prefix("max_versions=") {
+            current_config.max_versions = parse_math_string(val, current_config.max_versions);
+            max_versions_set = true;
+
+        } else if let Some(val) = line.strip_prefix("blake3=") {
+            current_config.checksum_blake3 = parse_switch("blake3", val, current_config.checksum_blake3);
+
+        } else if let Some(val) = line.strip_prefix("dedup=") {
+            current_config.dedup = parse_switch("dedup", val, current_config.dedup);
+
+        } else if let Some(val) = line.strip_prefix("storage=") {
+            match storage::StorageKind::parse(val) {
+                Some(kind) => current_config.storage = kind,
+                None => println!("Warning: storage should be local, memory or s3. Keeping {:?}.", current_config.storage),
+            }
+
+        } else if let Some(val) = line.strip_prefix("s3_endpoint=") {
+            current_config.s3.endpoint = val.trim().to_string();
+
+        } else if let Some(val) = line.strip_prefix("s3_bucket=") {
+            current_config.s3.bucket = val.trim().to_string();
+
+        } else if let Some(val) = line.strip_prefix("s3_region=") {
+            current_config.s3.region = val.trim().to_string();
+
+        } else if let Some(val) = line.strip_prefix("s3_access_key=") {
+            current_config.s3.access_key = val.trim().to_string();
+
+        } else if let Some(val) = line.strip_prefix("s3_secret_key=") {
+            current_config.s3.secret_key = val.trim().to_string();
+
+        } else if let Some(val) = line.strip_prefix("quota_total=") {
+            current_config.quota_total = parse_math_string(val, current_config.quota_total);
+
+        } else if let Some(val) = line.strip_prefix("quota_per_user=") {
+            current_config.quota_per_user = parse_math_string(val, current_config.quota_per_user);
+
+        } else if let Some(val) = line.strip_prefix("quota=") {
+            match val.trim().split_once(char::is_whitespace) {
+                Some((name, size)) => {
+                    let quota = parse_math_string(size, current_config.quota_per_user);
+                    current_config.user_quotas.insert(name.to_string(), quota);
+                }
+                None => println!("Warning: Could not read the quota line '{}'. Skipping it.", line),
+            }
+
+        } else if let Some(val) = line.strip_prefix("min_free_space=") {
+            current_config.min_free_space = parse_math_string(val, current_config.min_free_space);
+
+        } else if let Some(val) = line.strip_prefix("file_retention=") {
+            current_config.file_retention_secs = parse_math_string(val, current_config.file_retention_secs);
+
+        } else if let Some(val) = line.strip_prefix("expiry_choices=") {
+            current_config.expiry_choices = expiry::parse_choices(val);
         }
     }
+    if max_versions_set && current_config.max_versions > 0 && current_config.on_collision != staging::Collision::Overwrite {
+        println!("Warning: max_versions only does something with on_collision= overwrite. With {:?}, no file gets replaced, so no old copies will be kept.", current_config.on_collision);
+    }
     
     current_config
 });
@@ -141,6 +334,22 @@ static CONFIG: Lazy<AppConfig> = Lazy::new(|| {
     }
 }
 
+///Reads an on/off setting from the config.
+/// 
+/// * `name` - the setting, for the warning
+/// * `input` - what came after the `=`
+/// * `default` - kept if it is neither on nor off
+fn parse_switch(name: &str, input: &str, default: bool) -> bool {
+    match input.trim().to_lowercase().as_str() {
+        "on" | "true" | "yes" => true,
+        "off" | "false" | "no" => false,
+        _ => {
+            println!("Warning: {} should be on or off. Keeping it {}.", name, if default { "on" } else { "off" });
+            default
+        }
+    }
+}
+
 
 ///Ensures certificates for HTTPS by looking for certificates, and creating them if they don't exist.
 ///  - This is necessary for initialization
@@ -184,13 +393,14 @@ fn ensure_certificates() -> Result<(), Box<dyn std::error::Error>> {
 /// 
 /// * `env_path` - the path of the PASSWORD.env file
 /// * `new_password` - The new password string
-/// * `content` - represents the new_password in a format readily written to a fresh PASSWORD.env file
+/// * `content` - the argon2 hash of new_password, in a format readily written to a fresh PASSWORD.env file
+/// * Old PASSWORD.env files with a plaintext password get hashed in place.
 fn ensure_password() -> Result<(), Box<dyn std::error::Error>> {
     //ensure the passwords are there.
     let env_path = PathBuf::from("PASSWORD.env");
 
     if env_path.exists() {
-        return Ok(());
+        return migrate_plaintext_password(&env_path);
     }
 
     println!("!--------------------------------------------------!");
@@ -208,19 +418,42 @@ fn ensure_password() -> Result<(), Box<dyn std::error::Error>> {
         return Err("Password cannot be empty. You don't want that.".into());
     }
 
-    // Save the new password to file
-    let content = format!("APP_PASSWORD={}", new_password);
+    // Save the hash of the new password to file. never the password itself.
+    let content = password::env_line(&password::hash_password(new_password)?);
     fs::write(&env_path, content)?;
 
     println!("Password saved to 'PASSWORD.env'.");
     println!("~--------------------------------------------------~\n");
     
     // load the env file immediately.
-    dotenvy::from_filename("PASSWORD.env").ok();
+    dotenvy::from_filename_override("PASSWORD.env").ok();
 
     Ok(())
 }
 
+///Swaps a plaintext APP_PASSWORD for its argon2 hash.
+/// 
+/// * `env_path` - the path of the PASSWORD.env file
+/// * `stored` - whatever APP_PASSWORD currently is in the file
+/// * Does nothing if the file already holds a hash.
+fn migrate_plaintext_password(env_path: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    let stored = dotenvy::from_filename_iter(env_path)?
+        .flatten()
+        .find(|(key, _)| key == "APP_PASSWORD")
+        .map(|(_, value)| value)
+        .ok_or("APP_PASSWORD is missing from PASSWORD.env")?;
+
+    if !password::is_hashed(&stored) {
+        println!("Found a plaintext password in 'PASSWORD.env'. Hashing it now...");
+        fs::write(env_path, password::env_line(&password::hash_password(&stored)?))?;
+        println!("Password is now stored as an argon2 hash.");
+    }
+
+    //override so a stale APP_PASSWORD from the shell can't win.
+    dotenvy::from_filename_override(env_path)?;
+    Ok(())
+}
+
 
 
 use serde::Deserialize;
@@ -237,11 +470,22 @@ fn get_local_ip() -> Option<String> {
 ///Returns the current time of user
 /// 
 /// * `time` - The local time of the user in string
+///
 /// Changed to only grab the first 19 Characters in order to stop the time stamp from being too accurate and annoying.
 fn get_time() -> String{
     let mut time = chrono::offset::Local::now().to_string();
     time.truncate(19);
-    return time;
+    time
+}
+
+///Turns unix seconds into the same local time format as get_time.
+/// 
+/// * `unix_secs` - seconds since 1970, like the ones saved in shares.json
+fn format_time(unix_secs: i64) -> String {
+    match chrono::DateTime::from_timestamp(unix_secs, 0) {
+        Some(time) => time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => "unknown".to_string(),
+    }
 }
 
 
@@ -252,6 +496,24 @@ async fn main() {
     // tracing_subscriber::fmt::init();
     
     std::fs::create_dir_all("uploads").expect("Failed to create uploads folder");
+    //half written files from a crash never got renamed into place. nobody wants them.
+    let stale = staging::remove_stale(std::path::Path::new("uploads"));
+    if stale > 0 {
+        println!("Removed {} unfinished upload(s) left over from last time.", stale);
+    }
+    if CONFIG.dedup && !dedup::enabled() {
+        println!("Warning: dedup needs Linux or macOS. Files will be stored as they are.");
+    }
+    if CONFIG.storage != storage::StorageKind::Local {
+        println!("Uploads are kept in {:?} storage. The trash, versions, dedup, checksums, rename and move only work with local storage.", CONFIG.storage);
+    }
+    if CONFIG.storage == storage::StorageKind::S3 && (CONFIG.s3.endpoint.is_empty() || CONFIG.s3.bucket.is_empty()) {
+        println!("Warning: storage= s3 needs s3_endpoint and s3_bucket in config.ini. Uploads will fail until they are set.");
+    }
+    //files from before dedup was turned on. off the main thread, since it may need to hash everything once.
+    tokio::task::spawn_blocking(dedup::dedup_existing);
+    //files from before quotas were kept track of still take up room.
+    tokio::task::spawn_blocking(quota::count_existing);
     
     // 2. Ensure certificates exist before starting the router.
     if let Err(e) = ensure_certificates() {
@@ -264,14 +526,61 @@ async fn main() {
         eprintln!("Error setting password: {}", e);
         return;
     }
+    //4. The PASSWORD.env hash becomes the first admin account if there is no users file yet.
+    let seed_hash = env::var("APP_PASSWORD").unwrap_or_default();
+    if let Err(e) = users::ensure_users(&seed_hash) {
+        eprintln!("Error loading users: {}", e);
+        return;
+    }
+    if users::USERS.is_empty() {
+        eprintln!("Error loading users: {} has no valid accounts.", users::USERS_PATH);
+        return;
+    }
    
     
+    //only admins get past this one.
+    let admin_routes = Router::new()
+        .route("/admin", get(admin::admin_page))
+        .route("/admin/sessions", get(admin::list_sessions))
+        .route("/admin/sessions/{handle}", delete(admin::revoke_session))
+        .route("/dropboxes", get(dropbox::list_dropboxes).post(dropbox::create_dropbox))
+        .route("/trash", delete(trash::empty_trash)) //emptying the whole trash
+        .route("/admin/verify", post(checksums::verify)) //re-reads files to catch bit rot
+        .route("/dropboxes/{token}", delete(dropbox::revoke_dropbox))
+        .route("/dropboxes/{token}/files/{name}", get(dropbox::download_dropbox_file))
+        .route_layer(middleware::from_fn_with_state(users::Role::Admin, require_role));
+
+    //uploaders and admins.
+    let uploader_routes = Router::new()
+        .route("/upload", post(upload)) //the "website" the browser is in during the upload..?
+        .route("/folders", post(files::create_folder)) //new folders inside uploads
+        .route("/files/{*path}", delete(file_ops::delete_file)) //cleaning up, logged with who did it
+        //these win over the one above, so files with their names still need a way to be deleted.
+        .route("/files/rename", post(file_ops::rename_file).delete(file_ops::delete_taken_name))
+        .route("/files/move", post(file_ops::move_file).delete(file_ops::delete_taken_name))
+        .route("/files/bulk", post(file_ops::bulk).delete(file_ops::delete_taken_name))
+        .route("/trash", get(trash::list_trash)) //deleted things, until they get purged
+        .route("/trash/{id}", delete(trash::purge_item))
+        .route("/trash/{id}/restore", post(trash::restore_item))
+        .route("/versions/{id}/restore", post(versions::restore_version)) //an old copy becomes the current one
+        .route("/shares", get(shares::list_shares).post(shares::create_share))
+        .route("/shares/{token}", delete(shares::revoke_share))
+        .route("/tus", options(tus::tus_options).post(tus::tus_create)) //resumable uploads
+        .route("/tus/{id}", head(tus::tus_head).patch(tus::tus_patch).delete(tus::tus_delete))
+        .route_layer(middleware::from_fn_with_state(users::Role::Uploader, require_role));
+
     //define the routes that the "website" allows
     let protected_routes = Router::new()
         .route("/", get(index)) //the main dashboard
-        .route("/upload", post(upload)) //the "website" the browser is in during the upload..?
-        .route("/files", get(list_files)) //the files
-        .route("/download/{name}", get(download)) 
+        .route("/files", get(files::list_files)) //the files, with sizes and dates
+        .route("/quota", get(quota::show_quota)) //how much room is left
+        .route("/expiry", get(expiry::expiry_options)) //what the upload form can pick
+        .route("/download/{*name}", get(download)) //anything in uploads, folders too
+        .route("/versions", get(versions::list_versions)) //old copies of a file
+        .route("/versions/{id}", get(versions::download_version))
+        .route("/logout", post(logout))
+        .merge(uploader_routes)
+        .merge(admin_routes)
 
         .layer(
             ServiceBuilder::new()
@@ -286,6 +595,13 @@ async fn main() {
     
     let app = Router::new()
         .route("/login", get(login_form).post(login_submit))
+        .route("/s/{token}", get(shares::open_share).post(shares::open_share_with_password)) //public share links
+        .route(
+            "/r/{token}",
+            get(dropbox::open_dropbox)
+                .post(dropbox::dropbox_upload)
+                .layer(DefaultBodyLimit::max(CONFIG.max_upload_size as usize)),
+        ) //public drop box links
         .merge(protected_routes)
         .layer(CookieManagerLayer::new());
 
@@ -318,10 +634,28 @@ async fn main() {
 
     //give a special message if upload or download are maximum.
     if pretty_upload_speed <=0.0{println!("!~`Upload Speed is set to Maximum`~!");
-    }if pretty_download_speed <=0.0{println!("!~`Download Speed is set to Maximum`~!");
+    }
+    if pretty_download_speed <=0.0{println!("!~`Download Speed is set to Maximum`~!");
     }
     println!("-------------------------------------------------------------------------------------");
 
+    //sweep out dead sessions every minute so the store stays small.
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            ticker.tick().await;
+            session::SESSIONS.purge_expired();
+            lockout::LOGIN_GUARD.purge_stale();
+            shares::SHARES.purge_dead();
+            throttle::purge_idle();
+            trash::TRASH.purge_expired().await;
+            tus::purge_abandoned().await;
+            expiry::purge_expired().await;
+            dedup::collect_garbage().await;
+            checksums::CHECKSUMS.flush();
+        }
+    });
+
     //new stuff: 
     let handle = axum_server::Handle::new();
     let shutdown_handle = handle.clone();
@@ -337,27 +671,22 @@ async fn main() {
     // 2. Bind using axum-server with the TLS config
     axum_server::bind_rustls(addr, config)
         .handle(handle)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
+    //the checksums from the last minute aren't on disk yet.
+    checksums::CHECKSUMS.flush();
 }
 
 
-///Call the app password file, called PASSWORD.env
-static APP_PASSWORD: Lazy<String> = Lazy::new(|| {
-    dotenvy::from_filename("PASSWORD.env").ok(); // load file
-    env::var("APP_PASSWORD").expect("APP_PASSWORD not set")
-});
-
-
 ///calls for the index.html
 async fn index() -> Html<&'static str> {
     Html(include_str!("../index.html"))
 }
 
-///this is a struct for the password.
+///this is a struct for the username and password.
 #[derive(Deserialize)]
-struct LoginForm { password: String }
+struct LoginForm { username: String, password: String }
 
 ///calls the login.html
 async fn login_form() -> Html<&'static str> {
@@ -366,57 +695,161 @@ async fn login_form() -> Html<&'static str> {
 
 ///handles the login function of the software
 /// 
+/// * `addr` - where the login came from. Used for the lockout.
+/// * `headers` - the user agent gets saved with the session for the admin page.
 /// * `cookies` - The cookies for the session
-/// * Returns feedback for correct and incorrect logins.
-/// * `data.password` - the password returned from the login.html
+/// * Returns feedback for correct and incorrect logins, or a 429 if the IP is locked out.
+/// * `data.username` / `data.password` - the login returned from the login.html
 async fn login_submit(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     cookies: tower_cookies::Cookies,
     Form(data): Form<LoginForm>, 
-) -> Redirect {
+) -> Response {
     
-    if data.password == *APP_PASSWORD {
-    cookies.add(Cookie::new("auth", "ok"));
+    //locked out IPs don't even get their password checked.
+    if let Err(wait) = lockout::LOGIN_GUARD.begin(addr.ip()) {
+        println!("Blocked login from {} on {}. Locked out for {:.1}s more.", addr.ip(), get_time(), wait.as_secs_f64());
+        return too_many_attempts(wait);
+    }
+
+    //argon2 is slow on purpose, so keep it off the async threads.
+    let username = data.username.trim().to_string();
+    let attempt = data.password.clone();
+    let authenticated = tokio::task::spawn_blocking(move || users::authenticate(&username, &attempt))
+        .await
+        .unwrap_or(None);
+
+    if let Some(user) = authenticated {
+    //make a real session instead of a cookie anyone could type in.
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("unknown");
+    let id = match session::SESSIONS.create(&user.name, addr.ip(), user_agent) {
+        Ok(id) => id,
+        Err(e) => {
+            println!("ERROR! Could not make a session id: {}", e);
+            return Redirect::to("/login").into_response();
+        }
+    };
+    lockout::LOGIN_GUARD.record_success(addr.ip());
+    let cookie = Cookie::build((session::SESSION_COOKIE, id))
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .max_age(tower_cookies::cookie::time::Duration::seconds(CONFIG.session_lifetime_secs as i64));
+    cookies.add(cookie.into());
     //------------------------------------------------------------------------------------------------------
-    println!("Connected User to dashboard on {}",get_time());
+    println!("Connected '{}' ({}) from {} to dashboard on {}",user.name,user.role,addr.ip(),get_time());
     
-    Redirect::to("/")
+    Redirect::to("/").into_response()
     }
-    //try to incorporate an Anti Bruteforce technique?
 else {
     
-    println!("Password '{}' is incorrect.",data.password);
+    //never print what they typed either. a password in the username box happens more than you'd think.
+    let known = if users::USERS.contains_key(data.username.trim()) { "a known user" } else { "an unknown user" };
+    println!("Failed login for {} from {} on {}",known,addr.ip(),get_time());
+
+    if let Some(wait) = lockout::LOGIN_GUARD.record_failure(addr.ip()) {
+        println!("WARNING: {} is locked out of /login for {}s.", addr.ip(), wait.as_secs());
+        return too_many_attempts(wait);
+    }
     
-    Redirect::to("/login")  //go back to the login :)
+    Redirect::to("/login").into_response()  //go back to the login :)
 }}
 
+///The 429 a locked out client gets, with Retry-After so well behaved clients know when to come back.
+/// 
+/// * `wait` - how long until the lockout ends. Rounded up so they never come back a moment too early.
+fn too_many_attempts(wait: std::time::Duration) -> Response {
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after.to_string())],
+        "Too many failed logins. Try again later.",
+    )
+        .into_response()
+}
+
+///Ends the current session and sends the browser back to the login page.
+/// 
+/// * `cookies` - the session cookie gets removed from the browser too
+async fn logout(
+    Extension(user): Extension<users::AuthUser>,
+    cookies: Cookies,
+) -> Redirect {
+    if let Some(cookie) = cookies.get(session::SESSION_COOKIE) {
+        session::SESSIONS.remove(cookie.value());
+    }
+    cookies.remove(Cookie::build((session::SESSION_COOKIE, "")).path("/").into());
+
+    println!("'{}' logged out on {}",user.name,get_time());
+    Redirect::to("/login")
+}
+
 ///accept or reject users based on login or cookies.
 /// 
+/// * The logged in user goes into the request extensions as a `users::AuthUser`.
 async fn require_auth(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     cookies: Cookies,
-    req: Request<Body>,
+    mut req: Request<Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
     // take the cookie first so it lives long enough
-if cookies
-    .get("auth")
-    .map(|c| c.value() == "ok")
-    .unwrap_or(false)
-{
+let user = cookies
+    .get(session::SESSION_COOKIE)
+    .and_then(|c| session::SESSIONS.touch(c.value(), addr.ip()))
+    .and_then(|name| users::USERS.get(&name))
+    .map(|u| users::AuthUser { name: u.name.clone(), role: u.role });
+
+if let Some(user) = user {
+    req.extensions_mut().insert(user);
     Ok(next.run(req).await)
 } else {
     println!("System denied forceful entry on {}",get_time());
     Err(StatusCode::UNAUTHORIZED)
 }}
 
+///Rejects users whose role is below the one a route needs. Goes inside require_auth.
+/// 
+/// * `min_role` - the lowest role allowed through
+async fn require_role(
+    State(min_role): State<users::Role>,
+    Extension(user): Extension<users::AuthUser>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if user.role >= min_role {
+        Ok(next.run(req).await)
+    } else {
+        println!("Denied '{}' ({}) access to {} on {}. Needs {}.",user.name,user.role,req.uri().path(),get_time(),min_role);
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
 
 ///handles uploads from server to device
 /// 
-/// * `file` - new user file
+/// * `addr` - where the upload comes from, for the per client speed limit.
+/// * `user` - who is uploading, for the log.
+/// * `target` - `?path=` the folder to upload into. Left out means the top of uploads.
+/// * `file` - new user file. Folder uploads name it like `trip/day1/a.jpg` and the folders get made to match.
 /// * `path` - the path to the new upload. located in the uploads folder.
 /// * `chunk_size` - the speed from config file
 /// * `headers` - Give the ability to grab the size of the file before writing.
+/// * Scripts that send `Accept: application/json` get the stored names back instead of the redirect,
+///   since on_collision can give a file a different name than the one it was sent with.
 /// 
-async fn upload(headers: HeaderMap, mut multipart: Multipart) -> impl IntoResponse {
+async fn upload(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(user): Extension<users::AuthUser>,
+    Query(target): Query<files::FolderQuery>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
 
     let total_request_size: u64 = headers
 
@@ -426,141 +859,296 @@ async fn upload(headers: HeaderMap, mut multipart: Multipart) -> impl IntoRespon
         .unwrap_or(0);
 
         if total_request_size > CONFIG.max_upload_size {
-                    println!("file too large.");
-                    return (
-                        axum::http::StatusCode::PAYLOAD_TOO_LARGE,
-                        "File too big",
-                    )
-                        .into_response();
+                    return upload_failed(&user.name, "", UploadError::TooLarge);
+                }
+                //turn it away now if it can't fit. the chunks get checked again on the way in.
+                if let Err(e) = quota::check(Some(&user.name), total_request_size) {
+                    return upload_failed(&user.name, "", e);
                 }
 
                 let mut global_written : u64 = 0; //this is to keep everything normal
+                let Some(limiter) = throttle::Limiter::upload(addr.ip(), Some(&user.name)) else {
+                    println!("\n❌ Upload by '{}' turned away on {}: too many at once", user.name, get_time());
+                    return throttle::too_busy();
+                };
+                //X-Checksum-Sha256 goes for every file in the request. a `sha256` field before a file goes for just that one.
+                let request_sha256 = match checksums::expected(&headers) {
+                    Ok(expected) => expected,
+                    Err(e) => return upload_failed(&user.name, "", e),
+                };
+                let mut field_sha256: Option<String> = None;
+                //?expires_in= goes for every file. an `expires_in` field before a file goes for the files after it.
+                let mut expires_in = match expiry::pick(&target.expires_in) {
+                    Ok(expires_in) => expires_in,
+                    Err(e) => return upload_failed(&user.name, "", e),
+                };
                 println!("\nBeginning Upload Now...\n");
-    while let Some(mut field) = multipart.next_field().await.unwrap() {
+    let mut stored = vec![];
+    loop {
+        //a tab closing mid upload shows up here as an error, not a panic.
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return upload_failed(&user.name, "", e.into()),
+        };
+        if field.name() == Some("sha256") && field.file_name().is_none() {
+            let text = match field.text().await {
+                Ok(text) => text.trim().to_lowercase(),
+                Err(e) => return upload_failed(&user.name, "", e.into()),
+            };
+            field_sha256 = (!text.is_empty()).then_some(text);
+            continue;
+        }
+        if field.name() == Some("expires_in") && field.file_name().is_none() {
+            let text = match field.text().await {
+                Ok(text) => text,
+                Err(e) => return upload_failed(&user.name, "", e.into()),
+            };
+            expires_in = match expiry::pick(&text) {
+                Ok(expires_in) => expires_in,
+                Err(e) => return upload_failed(&user.name, "", e),
+            };
+            continue;
+        }
         if let Some(filename) = field.file_name().map(|s| s.to_string()) {
 
-            let file_name= field.file_name().map(|s| s.to_string());
-            let name_of_file = file_name.unwrap();
-            //block bad names and security flaws.
-            if name_of_file.contains('/') || name_of_file.contains('\\') || name_of_file.contains("..") {
+            let name_of_file = filename.clone();
+            //block bad names and security flaws. folders in the name are fine as long as they stay inside uploads.
+            let Some(key) = files::upload_key(&target.path, &filename, &user) else {
             println!("WARNING: Malicious path traversal attempt blocked: {}", name_of_file);
-            return (StatusCode::BAD_REQUEST, "Invalid filename").into_response();
-    }
+            return upload_failed(&user.name, &name_of_file, UploadError::BadRequest("Invalid filename"));
+    };
     
-            let path = PathBuf::from("uploads").join(&filename);
-            let file = File::create(&path).await.unwrap();
-            let chunk_size = 128*1024; //128KB Keep it static to use less data
-            let mut buf_writer = BufWriter::with_capacity(chunk_size, file);
-            
-            let mut last_print=Instant::now();
-            // 3. Process the incoming network chunks
-            while let Some(chunk) = field.chunk().await.unwrap() {
-                global_written += chunk.len() as u64;
-                //added a progress tracker here.
-            use std::io::Write; // Required for the flush() command below
-
-                let write_size = global_written as f64/(1024.0*1024.0);
-                let percentage = (global_written as f64/total_request_size as f64)*100.0;
-
-                // -- APPLYING THE UPLOAD SPEED LIMIT --
-                if CONFIG.upload_speed_bps > 0 {
-                
-                let seconds_for_chunk = chunk.len() as f64 / CONFIG.upload_speed_bps as f64;
-                let sleep_duration = std::time::Duration::from_secs_f64(seconds_for_chunk);
-        
-                // Force the server to pause, effectively throttling the upload
-                tokio::time::sleep(sleep_duration).await;
-            }
-                // Write the network chunk into RAM buffer. 
-                buf_writer.write_all(&chunk).await.unwrap();
-
-                //This slows the terminal but increases the speed of the upload. woohoo?
-                //yeah it's super fast.
-                if last_print.elapsed().as_millis()>200{
-                    print!("\rUploading '{}' || {:.2} Megabytes Written  {:.2}%",name_of_file,write_size,percentage);
-                    
-                    std::io::stdout().flush().unwrap();
-                    last_print=Instant::now();
-                }
-                
-            }
-            
-            //flush the writer if it's done.
-            buf_writer.flush().await.unwrap();
-            
+            let expected = field_sha256.take().or_else(|| request_sha256.clone());
+            let stored_as = match write_field(&user.name, Some(&user.name), &mut field, &key, &name_of_file, total_request_size, &mut global_written, u64::MAX, &limiter, expected.as_deref()).await {
+                Ok((_, stored_as)) => stored_as,
+                Err(e) => return upload_failed(&user.name, &name_of_file, e),
+            };
+            expiry::EXPIRY.set(&stored_as, expires_in);
+
             //added some pretty diagnostic stuff.
-            println!("\n   ⬆️ Uploaded '{}' to the dashboard on {}",name_of_file,get_time());
+            println!("\n   ⬆️ '{}' uploaded '{}' to the dashboard as '{}' on {}",user.name,name_of_file,stored_as,get_time());
+            stored.push(StoredName { name: name_of_file, stored_as });
         }
     }
-    Redirect::to("/").into_response()
+    let wants_json = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).is_some_and(|v| v.contains("application/json"));
+    if wants_json {
+        return Json(stored).into_response();
+    }
+    //back to the folder they were in.
+    match target.path.is_empty() {
+        true => Redirect::to("/").into_response(),
+        false => Redirect::to(&format!("/?path={}", files::url_encode(&target.path))).into_response(),
+    }
 }
 
-///list the files
+///Where one uploaded file ended up.
 /// 
-/// * `names` - the string of the names of the files in the upload folder.
-async fn list_files() -> Json<Vec<String>> {
-    let mut names = vec![];
-    if let Ok(entries) = fs::read_dir("uploads") {
-        for e in entries.flatten() {
-            if let Some(name) = e.file_name().to_str() {
-                names.push(name.to_string());
-            }
+/// * `name` - what the browser called it
+/// * `stored_as` - its path from the top of uploads, after on_collision had its say
+#[derive(serde::Serialize)]
+struct StoredName {
+    name: String,
+    stored_as: String,
+}
+
+///Logs a failed upload and turns the error into the response for the browser.
+/// 
+/// * `who` - the user or IP doing the upload
+/// * `name_of_file` - the file that failed, or "" if it broke before a file started
+/// * The partial file is already gone by now. StagedFile deletes it when it drops.
+fn upload_failed(who: &str, name_of_file: &str, error: UploadError) -> Response {
+    println!("\n❌ Upload of '{}' by '{}' failed on {} ({}): {}", name_of_file, who, get_time(), error.status().as_u16(), error);
+    error.into_response()
+}
+
+///Streams one file out of a multipart upload and into storage. Shared by upload and the drop box links.
+/// 
+/// * `who` - the user or IP doing the upload, for the log
+/// * `owner` - whose quota the file counts against
+/// * `field` - the multipart field holding the file
+/// * `key` - where the file goes, from the top of uploads
+/// * `name_of_file` - the name for the progress print
+/// * `total_request_size` - the Content-Length, for the percentage
+/// * `global_written` - bytes written so far in this request, across every file
+/// * `limit` - the most bytes this one file is allowed. Going over throws the file away and returns TooLarge.
+/// * `limiter` - the speed limits for this request
+/// * Nothing shows up under `key` until every byte is in and the checksum matched.
+/// * Returns how many bytes the file ended up with, and the key it ended up under. That's only not `key` when on_collision renamed it.
+#[allow(clippy::too_many_arguments)]
+async fn write_field(
+    who: &str,
+    owner: Option<&str>,
+    field: &mut Field<'_>,
+    key: &str,
+    name_of_file: &str,
+    total_request_size: u64,
+    global_written: &mut u64,
+    limit: u64,
+    limiter: &throttle::Limiter,
+    expected_sha256: Option<&str>,
+) -> Result<(u64, String), UploadError> {
+    let guard = upload_error::DisconnectGuard::new(who, name_of_file);
+    let mut incoming = Incoming {
+        name_of_file,
+        total_request_size,
+        global_written,
+        written: 0,
+        limit,
+        limiter,
+        hasher: Some(checksums::Hasher::new()),
+        expected_sha256,
+        digests: None,
+        reservation: quota::Reservation::start(owner),
+        last_print: Instant::now(),
+    };
+    let result = receive_field(field, key, &mut incoming).await;
+    guard.disarm();
+    let stored_as = result?;
+    quota::OWNERS.record(&stored_as, owner, incoming.written);
+    //checksums, dedup and versions work on real files. other storage goes without.
+    if let (Some(stored_at), Some(digests)) = (storage::STORAGE.local_path(&stored_as), &incoming.digests) {
+        dedup::store(&stored_at, &digests.sha256);
+        checksums::CHECKSUMS.record(&stored_at, digests);
+        versions::VERSIONS.record_upload(&stored_at, who);
+    }
+    Ok((incoming.written, stored_as))
+}
+
+///Everything write_field keeps track of while one file streams through.
+/// 
+/// * `written` - bytes of this file so far
+/// * `hasher` - taken once the last byte is in, which leaves the result in `digests`
+struct Incoming<'a> {
+    name_of_file: &'a str,
+    total_request_size: u64,
+    global_written: &'a mut u64,
+    written: u64,
+    limit: u64,
+    limiter: &'a throttle::Limiter,
+    hasher: Option<checksums::Hasher>,
+    expected_sha256: Option<&'a str>,
+    digests: Option<checksums::Digests>,
+    reservation: quota::Reservation,
+    last_print: Instant,
+}
+
+///The part of write_field that does the work. Split out so the DisconnectGuard wraps all of it.
+async fn receive_field(field: &mut Field<'_>, key: &str, incoming: &mut Incoming<'_>) -> Result<String, UploadError> {
+    //with reject on, don't make them send the whole thing just to be told no.
+    if storage::would_reject(key).await {
+        return Err(UploadError::Exists);
+    }
+    let body = futures_util::stream::unfold((field, incoming), |(field, incoming)| async move {
+        let chunk = next_chunk(field, incoming).await?;
+        Some((chunk, (field, incoming)))
+    });
+    //anything going wrong comes out of the stream as an error, and storage throws the file away for it.
+    Ok(storage::STORAGE.put(key, Box::pin(body)).await?)
+}
+
+///Pulls the next chunk off the network, counting, slowing and hashing it on the way.
+/// 
+/// * At the end of the field the checksum gets checked, so a file that doesn't match never gets stored.
+/// * Upload errors go back wrapped in an io::Error. UploadError::from unwraps them again.
+async fn next_chunk(field: &mut Field<'_>, incoming: &mut Incoming<'_>) -> Option<io::Result<axum::body::Bytes>> {
+    let chunk = match field.chunk().await {
+        Ok(Some(chunk)) => chunk,
+        Ok(None) => {
+            let digests = incoming.hasher.take()?.finish();
+            let checked = checksums::check(incoming.expected_sha256, &digests);
+            incoming.digests = Some(digests);
+            return checked.err().map(|e| Err(io::Error::other(e)));
         }
+        Err(e) => return Some(Err(io::Error::other(UploadError::from(e)))),
+    };
+    *incoming.global_written += chunk.len() as u64;
+    incoming.written += chunk.len() as u64;
+
+    //too big for whatever link or rule this is going through. throw it away.
+    if incoming.written > incoming.limit {
+        return Some(Err(io::Error::other(UploadError::TooLarge)));
+    }
+    //Content-Length can be missing or wrong, so the quota and the disk get checked as the bytes come.
+    if let Err(e) = incoming.reservation.take(chunk.len() as u64) {
+        return Some(Err(io::Error::other(e)));
+    }
+
+    incoming.limiter.take(chunk.len()).await;
+    //hash on the way through, so the file never has to be read again for its checksum.
+    if let Some(hasher) = &mut incoming.hasher {
+        hasher.update(&chunk);
+    }
+
+    //This slows the terminal but increases the speed of the upload. woohoo?
+    //yeah it's super fast.
+    if incoming.last_print.elapsed().as_millis() > 200 {
+        let write_size = *incoming.global_written as f64 / (1024.0 * 1024.0);
+        let percentage = (*incoming.global_written as f64 / incoming.total_request_size as f64) * 100.0;
+        print!("\rUploading '{}' || {:.2} Megabytes Written  {:.2}%", incoming.name_of_file, write_size, percentage);
+        let _ = io::stdout().flush();
+        incoming.last_print = Instant::now();
     }
-    Json(names)
+    Some(Ok(chunk))
 }
 
 ///Handles downloads from the program into the browser downloader.
 /// 
-/// * `name` - the name of the file as defined by the names section.
+/// * `addr` - where the download goes, for the per client speed limit.
+/// * `user` - who is downloading, for the log.
+/// * `name` - the path of the file from the top of uploads, like `photos/beach.jpg`.
+/// * `request_headers` - passed on for Range requests.
 /// * `response` - Hopefully resolves successfully.
-async fn download(Path(name): Path<String>) -> impl IntoResponse {
+async fn download(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(user): Extension<users::AuthUser>,
+    Path(name): Path<String>,
+    request_headers: HeaderMap,
+) -> impl IntoResponse {
 
     //block a bad name
-    if name.contains('/') || name.contains('\\') || name.contains("..") {
+    let Some(rel) = files::clean_path(&name) else {
         println!("WARNING: Malicious path traversal attempt blocked: {}", name);
         return (StatusCode::BAD_REQUEST, "Invalid filename").into_response();
+    };
+    if !files::may_see(&rel, &user) {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
     }
+    let file_name = rel.file_name().and_then(|n| n.to_str()).unwrap_or("download").to_string();
 
-    let path = PathBuf::from("uploads").join(&name);
-    //the file must exist.
-    if !path.exists() {
-        println!("ERROR! Path does not exist!\n");
-        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    let Some(limiter) = throttle::Limiter::download(addr.ip(), Some(&user.name)) else {
+        return throttle::too_busy().into_response();
+    };
+    let response = stream_file(&files::display_path(&rel), &file_name, &request_headers, limiter).await;
+    if response.status().is_success() {
+        //give the terminal some feedback for downloads
+        println!("⬇️ '{}' downloaded '{}' from the dashboard on {}",user.name,name,get_time());
     }
+    response
+}
+
+///Streams a file from storage to the browser. Shared by download and the public share links.
+/// 
+/// * `key` - the file, from the top of uploads
+/// * `name` - the name the browser should save it as
+/// * `request_headers` - for Range and If-Range, so downloads can resume and videos can seek
+/// * `limiter` - the speed limits for whoever is downloading
+async fn stream_file(key: &str, name: &str, request_headers: &HeaderMap, limiter: throttle::Limiter) -> Response {
     //the file must be accessible.
-    let file = match File::open(&path).await {
-        Ok(f) => f,
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::NotFound => {
-                println!("Download failed: File '{}' not found.", name);
-                return (StatusCode::NOT_FOUND, "File not found").into_response();
-            }
-            _ => {
-                println!("Download failed: File '{}' inaccessible. Error: {}", name, e);
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Can't open file").into_response();
-            }
-        },
+    let (file_size, modified) = match storage::STORAGE.stat(key).await {
+        Ok(info) => (info.size, info.modified),
+        Err(e) => return read_failed(name, e),
     };
 
-    let file_size = match file.metadata().await {
-        Ok(meta) => meta.len(),
-        Err(_) => {
-            return (StatusCode::INTERNAL_SERVER_ERROR, "Can't read metadata").into_response();
-        }
+    //the checksum makes the best ETag. without one, size and modified time are enough to tell if the file changed under a resuming download.
+    let checksum = storage::STORAGE.local_path(key).and_then(|path| checksums::CHECKSUMS.known(&path));
+    let etag = match &checksum {
+        Some(checksum) => format!("\"{}\"", checksum.sha256),
+        None => format!("\"{:x}-{:x}\"", file_size, modified),
     };
-   
-    //splitting the file up.
-    let chunk_size = 128*1024; //now controlled properly
-    let buf_reader = BufReader::with_capacity(chunk_size, file);
-
-    //have the stream adapt to the values it is given.
-
-    let stream = ReaderStream::new(buf_reader);
-    let body = Body::from_stream(stream);
 
     // Guess MIME type (or fallback to binary)
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
+    let mime = mime_guess::from_path(key).first_or_octet_stream();
     let mut headers = HeaderMap::new();
 
     headers.insert(
@@ -573,10 +1161,102 @@ async fn download(Path(name): Path<String>) -> impl IntoResponse {
         headers.insert(header::CONTENT_DISPOSITION, header_value);
     }
 
-    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(file_size)); //give a file size to the browser so that it can use its own time evaluation.
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Ok(header_value) = HeaderValue::from_str(&etag) {
+        headers.insert(header::ETAG, header_value);
+    }
+    if let Some(digest) = checksum.as_ref().and_then(checksums::digest_header)
+        && let Ok(header_value) = HeaderValue::from_str(&digest)
+    {
+        headers.insert("Digest", header_value);
+    }
+    if let Ok(header_value) = HeaderValue::from_str(&range::http_date(modified)) {
+        headers.insert(header::LAST_MODIFIED, header_value);
+    }
 
-    //give the terminal some feedback for downloads
-    println!("⬇️ User downloaded '{}' from the dashboard on {}",name,get_time());
-    (headers, body).into_response()
-    
-}
\ No newline at end of file
+    //a stale If-Range means the file changed, so ignore the Range and send everything.
+    let range_header = request_headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let if_range_ok = match request_headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+        Some(if_range) => range::if_range_matches(if_range, &etag, modified),
+        None => true,
+    };
+    let wanted = if if_range_ok {
+        range::parse_range(range_header, file_size)
+    } else {
+        range::RangeRequest::Full
+    };
+
+    //splitting the file up.
+    let chunk_size = 128*1024; //now controlled properly
+
+    match wanted {
+        range::RangeRequest::Full => {
+            let stream = match storage::STORAGE.get(key, None).await {
+                Ok(stream) => stream,
+                Err(e) => return read_failed(name, e),
+            };
+            //have the stream adapt to the values it is given. the speed limits from config.ini go on here.
+            let body = throttle::download_body(stream, limiter);
+
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(file_size)); //give a file size to the browser so that it can use its own time evaluation.
+
+            (headers, body).into_response()
+        }
+        range::RangeRequest::Unsatisfiable => {
+            if let Ok(header_value) = HeaderValue::from_str(&format!("bytes */{}", file_size)) {
+                headers.insert(header::CONTENT_RANGE, header_value);
+            }
+            headers.remove(header::CONTENT_DISPOSITION);
+            (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
+        }
+        range::RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            let stream = match storage::STORAGE.get(key, Some((start, end))).await {
+                Ok(stream) => stream,
+                Err(e) => return read_failed(name, e),
+            };
+            let length = end - start + 1;
+            let body = throttle::download_body(stream, limiter);
+
+            if let Ok(header_value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, file_size)) {
+                headers.insert(header::CONTENT_RANGE, header_value);
+            }
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+
+            (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+        }
+        range::RangeRequest::Partial(ranges) => {
+            let boundary = match session::random_token() {
+                Ok(token) => token[..32].to_string(),
+                Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Can't read file").into_response(),
+            };
+            let parts = range::MultipartRanges::new(ranges, file_size, mime.as_ref(), boundary);
+
+            if let Ok(header_value) = HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", parts.boundary)) {
+                headers.insert(header::CONTENT_TYPE, header_value);
+            }
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(parts.content_length()));
+
+            //one task writes the parts into a pipe, the browser reads from the other end.
+            let (writer, reader) = tokio::io::duplex(chunk_size);
+            tokio::spawn(parts.write_to(key.to_string(), writer));
+            let body = throttle::download_body(ReaderStream::new(reader), limiter);
+
+            (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+        }
+    }
+}
+
+///The response for a download that couldn't be read from storage.
+fn read_failed(name: &str, e: io::Error) -> Response {
+    match e.kind() {
+        io::ErrorKind::NotFound => {
+            println!("Download failed: File '{}' not found.", name);
+            (StatusCode::NOT_FOUND, "File not found").into_response()
+        }
+        _ => {
+            println!("Download failed: File '{}' inaccessible. Error: {}", name, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Can't open file").into_response()
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//Password hashing. Nothing in here ever writes a plaintext password to disk.
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+};
+
+///Hashes a password with Argon2id and a fresh random salt.
+///
+/// * Returns a PHC string, for example `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`
+pub fn hash_password(password: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut salt_bytes = [0u8; 16];
+    getrandom::fill(&mut salt_bytes)?;
+    let salt = SaltString::encode_b64(&salt_bytes).map_err(|e| e.to_string())?;
+
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| e.to_string())?;
+    Ok(hash.to_string())
+}
+
+///Checks a password against a stored PHC hash.
+///
+/// * The comparison inside argon2 is constant time, so it doesn't leak how close a guess was.
+/// * A broken hash string just counts as a wrong password.
+pub fn verify_password(password: &str, stored_hash: &str) -> bool {
+    match PasswordHash::new(stored_hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+///True if the value already looks like an argon2 PHC string and not a plaintext password.
+pub fn is_hashed(value: &str) -> bool {
+    value.starts_with("$argon2")
+}
+
+///Makes the line that goes in PASSWORD.env.
+///
+/// * Single quotes stop dotenvy from treating the `$` parts of the hash as variables.
+pub fn env_line(hash: &str) -> String {
+    format!("APP_PASSWORD='{}'", hash)
+}
@@ -0,0 +1,303 @@
+//Storage quotas: a cap on everything in uploads, a cap per user, and a bit of the disk that is never handed out.
+//Who uploaded what is kept in owners.json as uploads finish. Files that were there before get counted at startup,
+//against the server total but nobody in particular.
+//Uploads are checked against what's left before they start, and again with every chunk, since Content-Length
+//can be missing or wrong. Running out gives a 507.
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Mutex,
+};
+use axum::{
+    extract::Extension,
+    response::{IntoResponse, Response},
+    Json,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{CONFIG, files, storage, upload_error::UploadError, users};
+
+const OWNERS_PATH: &str = "owners.json";
+
+///Who uploaded a file and how big it was then. The size only counts for storage that isn't on this disk,
+///local files get measured.
+///
+/// * `uploaded` - unix seconds when the upload finished. The modified time can't say, dedup gives a file the
+///   modified time of the first copy of its bytes. None for files that were there before rShare kept track.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Owned {
+    owner: Option<String>,
+    size: u64,
+    #[serde(default)]
+    uploaded: Option<i64>,
+}
+
+///Every file rShare knows the uploader of, keyed by path from the top of uploads.
+pub struct OwnerStore {
+    files: Mutex<HashMap<String, Owned>>,
+}
+
+pub static OWNERS: Lazy<OwnerStore> = Lazy::new(|| {
+    let files: HashMap<String, Owned> = fs::read_to_string(OWNERS_PATH)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    OwnerStore { files: Mutex::new(files) }
+});
+
+///Bytes uploaded but not stored yet, so two uploads at once can't both fit in the same space.
+/// * `total` - every upload going on
+/// * `users` - just the ones each user is doing
+#[derive(Default)]
+struct InFlight {
+    total: u64,
+    users: HashMap<String, u64>,
+}
+
+static IN_FLIGHT: Lazy<Mutex<InFlight>> = Lazy::new(Mutex::default);
+
+impl OwnerStore {
+    fn save(files: &HashMap<String, Owned>) {
+        match serde_json::to_string_pretty(files) {
+            Ok(json) => {
+                if let Err(e) = fs::write(OWNERS_PATH, json) {
+                    println!("ERROR! Could not save {}: {}", OWNERS_PATH, e);
+                }
+            }
+            Err(e) => println!("ERROR! Could not save {}: {}", OWNERS_PATH, e),
+        }
+    }
+
+    ///Notes who a finished upload belongs to.
+    ///
+    /// * `owner` - None for files nobody in particular uploaded
+    pub fn record(&self, key: &str, owner: Option<&str>, size: u64) {
+        let mut files = self.files.lock().unwrap();
+        let uploaded = Some(chrono::Utc::now().timestamp());
+        files.insert(key.to_string(), Owned { owner: owner.map(str::to_string), size, uploaded });
+        Self::save(&files);
+    }
+
+    ///When a file's upload finished, in unix seconds, if rShare was there for it.
+    pub fn uploaded_at(&self, key: &str) -> Option<i64> {
+        self.files.lock().unwrap().get(key).and_then(|owned| owned.uploaded)
+    }
+
+    ///Whether `user` may delete, rename or move what's at `key`. Admins always can, everyone else only their own.
+    ///
+    /// * A folder is theirs if every file in it is. An empty one is anyone's, since there's nothing in it to lose.
+    /// * Files nobody in particular uploaded, like ones from before rShare kept track, are the admin's.
+    pub fn may_change(&self, key: &str, user: &users::AuthUser, is_dir: bool) -> bool {
+        if user.role >= users::Role::Admin {
+            return true;
+        }
+        let files = self.files.lock().unwrap();
+        let mut inside = files.iter().filter(|(k, _)| Path::new(k).starts_with(key)).peekable();
+        if inside.peek().is_none() {
+            return is_dir;
+        }
+        inside.all(|(_, owned)| owned.owner.as_deref() == Some(user.name.as_str()))
+    }
+
+    ///Keeps the owner with a file or folder when it gets renamed or moved.
+    pub fn moved(&self, from: &str, to: &str) {
+        let mut files = self.files.lock().unwrap();
+        if files::rekey(&mut files, from, to) {
+            Self::save(&files);
+        }
+    }
+
+    ///Forgets a file or folder that was deleted for good.
+    pub fn removed(&self, key: &str) {
+        let mut files = self.files.lock().unwrap();
+        let before = files.len();
+        files.retain(|k, _| !Path::new(k).starts_with(key));
+        if files.len() < before {
+            Self::save(&files);
+        }
+    }
+
+    ///Bytes in uploads in total, and how many of them are `user`'s.
+    ///
+    /// * Things in the trash count. Their owners go with them to `.trash/<id>`, and they still take up the disk.
+    /// * Local files that aren't there any more, like ones deleted by hand, don't count. If they come back, they count again.
+    fn usage(&self, user: Option<&str>) -> (u64, u64) {
+        //a copy, so uploads finishing don't wait on a stat of every file.
+        let files: Vec<(String, Owned)> = self.files.lock().unwrap().iter().map(|(k, o)| (k.clone(), o.clone())).collect();
+        let (mut total, mut mine) = (0, 0);
+        for (key, owned) in &files {
+            let size = match storage::STORAGE.local_path(key) {
+                Some(path) => match fs::metadata(path) {
+                    Ok(meta) if meta.is_file() => meta.len(),
+                    _ => continue,
+                },
+                None => owned.size,
+            };
+            total += size;
+            if user.is_some() && owned.owner.as_deref() == user {
+                mine += size;
+            }
+        }
+        (total, mine)
+    }
+}
+
+///The most a user may keep in uploads. 0 means no cap.
+fn user_quota(user: &str) -> u64 {
+    CONFIG.user_quotas.get(user).copied().unwrap_or(CONFIG.quota_per_user)
+}
+
+///Bytes free on the disk uploads is on. None if we can't tell, or uploads aren't kept on this disk.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] //the field types differ between unix flavours.
+fn free_space() -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = storage::STORAGE.local_path("")?;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    //all zeros is a valid statvfs, and statvfs only writes into it.
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return None;
+    }
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space() -> Option<u64> {
+    None
+}
+
+///One upload's claim on what's left. Bytes get taken as they come in, and handed back when it's dropped,
+///by which point they're either stored and in owners.json or thrown away.
+pub struct Reservation {
+    owner: Option<String>,
+    total_used: u64,
+    user_used: u64,
+    taken: u64,
+}
+
+impl Reservation {
+    ///Starts a claim for an upload by `owner`. Works out how much is used right now.
+    pub fn start(owner: Option<&str>) -> Reservation {
+        let (total_used, user_used) = OWNERS.usage(owner);
+        Reservation { owner: owner.map(str::to_string), total_used, user_used, taken: 0 }
+    }
+
+    ///Checks `bytes` more would fit, without taking them.
+    pub fn fits(&self, bytes: u64) -> Result<(), UploadError> {
+        let in_flight = IN_FLIGHT.lock().unwrap();
+        let user_in_flight = self.owner.as_ref().and_then(|owner| in_flight.users.get(owner)).copied().unwrap_or(0);
+        self.check(bytes, in_flight.total, user_in_flight)
+    }
+
+    ///Takes `bytes` more for this upload, or says why they don't fit.
+    pub fn take(&mut self, bytes: u64) -> Result<(), UploadError> {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        let user_in_flight = self.owner.as_ref().and_then(|owner| in_flight.users.get(owner)).copied().unwrap_or(0);
+        self.check(bytes, in_flight.total, user_in_flight)?;
+        in_flight.total += bytes;
+        if let Some(owner) = &self.owner {
+            *in_flight.users.entry(owner.clone()).or_default() += bytes;
+        }
+        self.taken += bytes;
+        Ok(())
+    }
+
+    fn check(&self, bytes: u64, total_in_flight: u64, user_in_flight: u64) -> Result<(), UploadError> {
+        if CONFIG.quota_total > 0 && self.total_used + total_in_flight + bytes > CONFIG.quota_total {
+            return Err(UploadError::OverQuota);
+        }
+        if let Some(owner) = &self.owner {
+            let quota = user_quota(owner);
+            if quota > 0 && self.user_used + user_in_flight + bytes > quota {
+                return Err(UploadError::OverQuota);
+            }
+        }
+        //bytes already on their way are on the disk already, so only these count against what's free.
+        if free_space().is_some_and(|free| free < CONFIG.min_free_space.saturating_add(bytes)) {
+            return Err(UploadError::DiskFull);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        in_flight.total = in_flight.total.saturating_sub(self.taken);
+        if let Some(owner) = &self.owner
+            && let Some(bytes) = in_flight.users.get_mut(owner)
+        {
+            *bytes = bytes.saturating_sub(self.taken);
+            if *bytes == 0 {
+                in_flight.users.remove(owner);
+            }
+        }
+    }
+}
+
+///Checks an upload of `bytes` by `owner` fits before any of it comes in.
+pub fn check(owner: Option<&str>, bytes: u64) -> Result<(), UploadError> {
+    Reservation::start(owner).fits(bytes)
+}
+
+///Counts files that were in uploads before rShare kept track, against nobody. Blocking, called once at startup.
+pub fn count_existing() {
+    let Some(root) = storage::STORAGE.local_path("") else {
+        return;
+    };
+    let mut found = vec![];
+    find_files(&root, &mut found);
+    let mut files = OWNERS.files.lock().unwrap();
+    let before = files.len();
+    for (key, size) in found {
+        files.entry(key).or_insert(Owned { owner: None, size, uploaded: None });
+    }
+    if files.len() > before {
+        OwnerStore::save(&files);
+    }
+}
+
+fn find_files(folder: &Path, found: &mut Vec<(String, u64)>) {
+    let Ok(entries) = fs::read_dir(folder) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if files::is_own_name(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let path = entry.path();
+        match entry.metadata() {
+            Ok(meta) if meta.is_dir() => find_files(&path, found),
+            Ok(meta) if meta.is_file() => {
+                if let Some(key) = files::uploads_key(&path) {
+                    found.push((key, meta.len()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+///What GET /quota sends back. A limit of None means no cap.
+///
+/// * `free` - bytes left on the disk, not counting the part kept free. None for storage that isn't on this disk.
+#[derive(Serialize)]
+pub struct QuotaInfo {
+    used: u64,
+    limit: Option<u64>,
+    total_used: u64,
+    total_limit: Option<u64>,
+    free: Option<u64>,
+}
+
+///GET /quota. How much room the logged in user has left.
+pub async fn show_quota(Extension(user): Extension<users::AuthUser>) -> Response {
+    let (total_used, used) = OWNERS.usage(Some(&user.name));
+    let limit = Some(user_quota(&user.name)).filter(|q| *q > 0);
+    let total_limit = Some(CONFIG.quota_total).filter(|q| *q > 0);
+    let free = free_space().map(|free| free.saturating_sub(CONFIG.min_free_space));
+    Json(QuotaInfo { used, limit, total_used, total_limit, free }).into_response()
+}
@@ -0,0 +1,253 @@
+//HTTP Range requests. Lets browsers resume downloads and video players seek.
+use futures_util::StreamExt;
+use tokio::io::{AsyncWriteExt, DuplexStream};
+
+use crate::storage;
+
+///More ranges than this in one request and we just send the whole file. Stops silly requests
+///like a thousand one byte ranges.
+const MAX_RANGES: usize = 16;
+
+///What the Range header asked for.
+///
+/// * `Full` - no Range header, or one we ignore. Send the whole file with a 200.
+/// * `Partial` - one or more byte ranges, inclusive on both ends. Send a 206.
+/// * `Unsatisfiable` - every range was past the end of the file. Send a 416.
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    Full,
+    Partial(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+///Reads a Range header like `bytes=0-499, 1000-, -200`.
+///
+/// * `header` - the raw Range header, if there was one
+/// * `size` - the size of the file in bytes
+/// * Broken headers are ignored and count as Full, like the HTTP spec says.
+/// * Ranges that overlap or touch get joined, so asking for `0-` sixteen times sends the file once.
+pub fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+    let Some(specs) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = Vec::new();
+    let mut any_spec = false;
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        any_spec = true;
+        let Some((start, end)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let (start, end) = (start.trim(), end.trim());
+
+        let range = if start.is_empty() {
+            //"-500" means the last 500 bytes.
+            let Ok(suffix) = end.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            if suffix == 0 || size == 0 {
+                None
+            } else {
+                Some((size.saturating_sub(suffix), size - 1))
+            }
+        } else {
+            let Ok(start) = start.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+            let end = if end.is_empty() {
+                u64::MAX
+            } else {
+                match end.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if start >= size { None } else { Some((start, end.min(size - 1))) }
+        };
+
+        //ranges past the end get skipped. only if all of them are past the end is it a 416.
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+
+    if !any_spec || ranges.len() > MAX_RANGES {
+        RangeRequest::Full
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(coalesce(ranges))
+    }
+}
+
+///Sorts ranges and joins any that overlap or sit right next to each other.
+fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut joined: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match joined.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => joined.push((start, end)),
+        }
+    }
+    joined
+}
+
+///Checks If-Range. If it doesn't match, the file changed and the client has to start over with the full file.
+///
+/// * `if_range` - the raw If-Range header. Either an ETag or an HTTP date.
+/// * `etag` - the current ETag of the file
+/// * `last_modified` - the current modified time of the file, in unix seconds
+pub fn if_range_matches(if_range: &str, etag: &str, last_modified: i64) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with("W/") {
+        //weak tags never count for ranges.
+        return false;
+    }
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+    match chrono::DateTime::parse_from_rfc2822(if_range) {
+        Ok(date) => date.timestamp() == last_modified,
+        Err(_) => false,
+    }
+}
+
+///Formats unix seconds as an HTTP date, like `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(unix_secs: i64) -> String {
+    match chrono::DateTime::from_timestamp(unix_secs, 0) {
+        Some(time) => time.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        None => String::new(),
+    }
+}
+
+///The pieces of a multipart/byteranges body, worked out before streaming so the Content-Length is exact.
+///
+/// * `part_headers` - the boundary and headers that go in front of each range
+/// * `closing` - the last boundary
+pub struct MultipartRanges {
+    pub boundary: String,
+    part_headers: Vec<String>,
+    closing: String,
+    ranges: Vec<(u64, u64)>,
+}
+
+impl MultipartRanges {
+    pub fn new(ranges: Vec<(u64, u64)>, size: u64, content_type: &str, boundary: String) -> Self {
+        let part_headers = ranges
+            .iter()
+            .map(|(start, end)| {
+                format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, start, end, size
+                )
+            })
+            .collect();
+        let closing = format!("\r\n--{}--\r\n", boundary);
+        MultipartRanges { boundary, part_headers, closing, ranges }
+    }
+
+    ///How many bytes the whole body will be.
+    pub fn content_length(&self) -> u64 {
+        let headers: u64 = self.part_headers.iter().map(|h| h.len() as u64).sum();
+        let data: u64 = self.ranges.iter().map(|(start, end)| end - start + 1).sum();
+        headers + data + self.closing.len() as u64
+    }
+
+    ///Writes every part into the pipe. Runs in its own task while the other end streams to the browser.
+    ///
+    /// * An error here means the browser went away or storage stopped answering, so it stops quietly.
+    pub async fn write_to(self, key: String, mut pipe: DuplexStream) -> std::io::Result<()> {
+        for ((start, end), part_header) in self.ranges.iter().zip(&self.part_headers) {
+            pipe.write_all(part_header.as_bytes()).await?;
+            let mut part = storage::STORAGE.get(&key, Some((*start, *end))).await?;
+            while let Some(chunk) = part.next().await {
+                pipe.write_all(&chunk?).await?;
+            }
+        }
+        pipe.write_all(self.closing.as_bytes()).await?;
+        pipe.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(ranges.to_vec())
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse_range(Some("bytes=0-499"), 1000), partial(&[(0, 499)]));
+        assert_eq!(parse_range(Some("bytes=500-"), 1000), partial(&[(500, 999)]));
+        assert_eq!(parse_range(Some(" bytes= 10 - 20 "), 1000), partial(&[(10, 20)]));
+        //an end past the file stops at the last byte.
+        assert_eq!(parse_range(Some("bytes=900-5000"), 1000), partial(&[(900, 999)]));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse_range(Some("bytes=-200"), 1000), partial(&[(800, 999)]));
+        //asking for more than there is gets the whole file as a range.
+        assert_eq!(parse_range(Some("bytes=-5000"), 1000), partial(&[(0, 999)]));
+        assert_eq!(parse_range(Some("bytes=-0"), 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=-10"), 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn overlapping_ranges_get_joined() {
+        assert_eq!(parse_range(Some("bytes=0-99, 50-149, 150-199"), 1000), partial(&[(0, 199)]));
+        assert_eq!(parse_range(Some("bytes=500-599, 0-9, -100"), 1000), partial(&[(0, 9), (500, 599), (900, 999)]));
+        let many = format!("bytes={}", vec!["0-"; MAX_RANGES].join(","));
+        assert_eq!(parse_range(Some(&many), 1000), partial(&[(0, 999)]));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_range(Some("bytes=1000-"), 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=2000-3000, 5000-"), 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-"), 0), RangeRequest::Unsatisfiable);
+        //one range inside the file is enough for a 206.
+        assert_eq!(parse_range(Some("bytes=2000-3000, 0-9"), 1000), partial(&[(0, 9)]));
+    }
+
+    #[test]
+    fn broken_headers_mean_the_whole_file() {
+        for header in ["", "bytes=", "bytes=,", "items=0-9", "bytes=abc", "bytes=9-0", "bytes=a-9", "bytes=0-b", "bytes=--5", "bytes=0-9,x"] {
+            assert_eq!(parse_range(Some(header), 1000), RangeRequest::Full, "{:?}", header);
+        }
+        assert_eq!(parse_range(None, 1000), RangeRequest::Full);
+        let too_many = format!("bytes={}", (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 10, i * 10)).collect::<Vec<_>>().join(","));
+        assert_eq!(parse_range(Some(&too_many), 1000), RangeRequest::Full);
+    }
+
+    #[test]
+    fn coalesce_sorts_and_joins() {
+        assert_eq!(coalesce(vec![(10, 19), (0, 9)]), vec![(0, 19)]);
+        assert_eq!(coalesce(vec![(0, 5), (7, 9)]), vec![(0, 5), (7, 9)]);
+        assert_eq!(coalesce(vec![(0, 100), (10, 20)]), vec![(0, 100)]);
+        assert_eq!(coalesce(vec![(5, u64::MAX), (0, 4)]), vec![(0, u64::MAX)]);
+        assert_eq!(coalesce(vec![]), vec![]);
+    }
+
+    #[test]
+    fn if_range_matches_strong_etags_and_dates() {
+        let etag = "\"abc123\"";
+        assert!(if_range_matches("\"abc123\"", etag, 0));
+        assert!(!if_range_matches("\"abc124\"", etag, 0));
+        assert!(!if_range_matches("W/\"abc123\"", etag, 0));
+
+        let modified = 784111777;
+        let date = http_date(modified);
+        assert_eq!(date, "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(if_range_matches(&date, etag, modified));
+        assert!(!if_range_matches(&date, etag, modified + 1));
+        assert!(!if_range_matches("not a date", etag, modified));
+    }
+}
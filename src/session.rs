@@ -0,0 +1,168 @@
+//Server side sessions. The browser only ever gets a random id, everything else stays here.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{CONFIG, get_time};
+
+///The name of the cookie that carries the session id
+pub const SESSION_COOKIE: &str = "session";
+
+///A single logged in browser.
+///
+/// * `user` - the username this session belongs to
+/// * `handle` - a second random id that is safe to show on the admin page. The real id never leaves the cookie.
+/// * `ip` - the address of the last request
+/// * `user_agent` - the browser it logged in from
+/// * `logged_in_at` - readable login time for the admin page
+/// * `created` - when the user logged in. Used for the absolute expiry.
+/// * `last_seen` - the last time this session made a request. Used for the idle expiry.
+pub struct Session {
+    pub user: String,
+    pub handle: String,
+    pub ip: IpAddr,
+    pub user_agent: String,
+    pub logged_in_at: String,
+    pub created: Instant,
+    pub last_seen: Instant,
+}
+
+impl Session {
+    ///checks both the idle timeout and the absolute lifetime from the config file.
+    fn is_expired(&self, now: Instant) -> bool {
+        let idle = Duration::from_secs(CONFIG.session_idle_secs);
+        let lifetime = Duration::from_secs(CONFIG.session_lifetime_secs);
+        now.duration_since(self.last_seen) > idle || now.duration_since(self.created) > lifetime
+    }
+}
+
+///What the admin page gets to see about a session.
+///
+/// * `current` - true for the session that asked, so the page can warn before you revoke yourself
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub handle: String,
+    pub user: String,
+    pub ip: String,
+    pub user_agent: String,
+    pub logged_in_at: String,
+    pub idle_secs: u64,
+    pub current: bool,
+}
+
+///Holds every live session, keyed by the session id.
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+pub static SESSIONS: Lazy<SessionStore> = Lazy::new(|| SessionStore {
+    sessions: Mutex::new(HashMap::new()),
+});
+
+impl SessionStore {
+    ///Makes a new session for a user and returns its id.
+    ///
+    /// * `user` - the username that just logged in
+    /// * `ip` / `user_agent` - where they logged in from, for the admin page
+    /// * `id` - 32 random bytes from the OS, hex encoded. Can't be guessed or forged.
+    pub fn create(&self, user: &str, ip: IpAddr, user_agent: &str) -> Result<String, getrandom::Error> {
+        let id = random_token()?;
+        let handle = random_token()?;
+        let now = Instant::now();
+
+        let mut sessions = self.sessions.lock().unwrap();
+        //clean up old sessions so the map doesn't grow forever.
+        sessions.retain(|_, s| !s.is_expired(now));
+        sessions.insert(
+            id.clone(),
+            Session {
+                user: user.to_string(),
+                handle,
+                ip,
+                user_agent: user_agent.to_string(),
+                logged_in_at: get_time(),
+                created: now,
+                last_seen: now,
+            },
+        );
+        Ok(id)
+    }
+
+    ///Checks that a session id is real and still alive, and bumps its last seen time.
+    ///
+    /// * `ip` - where this request came from. Phones hop between networks so keep it fresh.
+    /// * Returns the username of the session.
+    /// * Returns None for unknown or expired ids. Expired ones get removed.
+    pub fn touch(&self, id: &str, ip: IpAddr) -> Option<String> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+
+        match sessions.get_mut(id) {
+            Some(session) if !session.is_expired(now) => {
+                session.last_seen = now;
+                session.ip = ip;
+                Some(session.user.clone())
+            }
+            Some(_) => {
+                sessions.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    ///Ends a session by its real id. Used by /logout.
+    pub fn remove(&self, id: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().remove(id)
+    }
+
+    ///Ends a session by its public handle. Used by the admin page.
+    ///
+    /// * Returns the removed session so the caller can log whose it was.
+    pub fn revoke(&self, handle: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let id = sessions.iter().find(|(_, s)| s.handle == handle).map(|(id, _)| id.clone())?;
+        sessions.remove(&id)
+    }
+
+    ///Lists every live session for the admin page, most recently active first.
+    ///
+    /// * `current_id` - the session id of whoever is asking
+    pub fn list(&self, current_id: Option<&str>) -> Vec<SessionInfo> {
+        let now = Instant::now();
+        let sessions = self.sessions.lock().unwrap();
+        let mut list: Vec<SessionInfo> = sessions
+            .iter()
+            .filter(|(_, s)| !s.is_expired(now))
+            .map(|(id, s)| SessionInfo {
+                handle: s.handle.clone(),
+                user: s.user.clone(),
+                ip: s.ip.to_string(),
+                user_agent: s.user_agent.clone(),
+                logged_in_at: s.logged_in_at.clone(),
+                idle_secs: now.duration_since(s.last_seen).as_secs(),
+                current: Some(id.as_str()) == current_id,
+            })
+            .collect();
+        list.sort_by_key(|s| s.idle_secs);
+        list
+    }
+
+    ///Drops every expired session. Called from a background task in main.
+    pub fn purge_expired(&self) {
+        let now = Instant::now();
+        self.sessions.lock().unwrap().retain(|_, s| !s.is_expired(now));
+    }
+}
+
+///Makes a random hex string from 32 bytes of OS randomness.
+pub fn random_token() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
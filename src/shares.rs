@@ -0,0 +1,501 @@
+//Public share links. Lets someone without a password grab one file.
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::Path as FsPath,
+    sync::Mutex,
+};
+use axum::{
+    extract::{ConnectInfo, Extension, Path},
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tower_cookies::{Cookie, Cookies, cookie::SameSite};
+
+use crate::{files, format_time, get_time, lockout, password, range, session, storage, stream_file, throttle, too_many_attempts, users};
+
+const SHARES_PATH: &str = "shares.json";
+///How long after a counted download the same client can send Range requests for the rest of the file, like a video
+///player seeking or a browser resuming, without using up another one. Counted from the download, not from the last request.
+const RANGE_PASS_SECS: i64 = 15 * 60;
+///How long the right link password works for. Long enough to resume or seek, without the link staying open for good.
+const UNLOCK_SECS: i64 = 15 * 60;
+///The cookie an Unlock goes out in. It's only sent back to the one link's url.
+const UNLOCK_COOKIE: &str = "share_unlock";
+
+///One share link, as saved in shares.json.
+///
+/// * `token` - the random part of the `/s/{token}` url
+/// * `file` - the path of the file from the top of uploads, like `photos/beach.jpg`
+/// * `expires_at` - unix seconds. None means it never expires.
+/// * `max_downloads` - None means no limit
+/// * `password_hash` - argon2 hash of the optional link password
+/// * `passes` - who can still fetch the rest of a download they started, see RangePass
+/// * `unlocks` - browsers that typed in the right password a moment ago, see Unlock
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ShareLink {
+    pub token: String,
+    pub file: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<i64>,
+    pub max_downloads: Option<u32>,
+    pub downloads: u32,
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub passes: Vec<RangePass>,
+    #[serde(default)]
+    pub unlocks: Vec<Unlock>,
+}
+
+///Handed out with every counted download. Range requests from `ip` that don't start at the beginning of the file
+///are the rest of that download until `until` (unix seconds), and don't count again.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RangePass {
+    ip: IpAddr,
+    until: i64,
+}
+
+///Handed out for the right link password, in a cookie. Until `until` (unix seconds), GETs with it skip the
+///password page, so a password protected download can be resumed or seeked through like any other.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Unlock {
+    key: String,
+    until: i64,
+}
+
+impl ShareLink {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|t| now >= t)
+    }
+
+    fn is_used_up(&self) -> bool {
+        self.max_downloads.is_some_and(|max| self.downloads >= max)
+    }
+
+    ///Whether its file is in the trash. The link comes back to life if the file gets restored.
+    fn is_trashed(&self) -> bool {
+        files::clean_path(&self.file).is_none()
+    }
+
+    ///A link is dead once it is past its expiry or out of downloads, or while its file is in the trash.
+    fn is_dead(&self, now: i64) -> bool {
+        self.is_expired(now) || self.is_used_up() || self.is_trashed()
+    }
+
+    fn is_unlocked(&self, key: &str, now: i64) -> bool {
+        self.unlocks.iter().any(|unlock| unlock.key == key && now < unlock.until)
+    }
+
+    fn has_pass(&self, ip: IpAddr, now: i64) -> bool {
+        self.passes.iter().any(|pass| pass.ip == ip && now < pass.until)
+    }
+
+    ///Whether `ip` can still get anything through the link. A used up link still works for the rest of the
+    ///downloads that used it up, for a while.
+    fn lets_in(&self, ip: IpAddr, now: i64) -> bool {
+        if self.is_expired(now) || self.is_trashed() {
+            return false;
+        }
+        !self.is_used_up() || self.has_pass(ip, now)
+    }
+
+    ///Dead, and nobody can use it any more either. Safe to throw away.
+    fn is_gone(&self, now: i64) -> bool {
+        self.is_expired(now) || (self.is_used_up() && self.passes.iter().all(|pass| now >= pass.until))
+    }
+}
+
+///What the dashboard gets to see about a link. Leaves out the password hash.
+///
+/// * `token` / `url` - None for people other than whoever made the link or an admin. Anyone with them can download.
+#[derive(Serialize)]
+pub struct ShareInfo {
+    pub token: Option<String>,
+    pub url: Option<String>,
+    pub file: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub downloads: u32,
+    pub max_downloads: Option<u32>,
+    pub has_password: bool,
+}
+
+impl From<&ShareLink> for ShareInfo {
+    fn from(link: &ShareLink) -> Self {
+        ShareInfo {
+            token: Some(link.token.clone()),
+            url: Some(format!("/s/{}", link.token)),
+            file: link.file.clone(),
+            created_by: link.created_by.clone(),
+            created_at: link.created_at.clone(),
+            expires_at: link.expires_at.map(format_time),
+            downloads: link.downloads,
+            max_downloads: link.max_downloads,
+            has_password: link.password_hash.is_some(),
+        }
+    }
+}
+
+///Every share link, keyed by token. Saved to shares.json after each change so links survive a restart.
+pub struct ShareStore {
+    links: Mutex<HashMap<String, ShareLink>>,
+}
+
+pub static SHARES: Lazy<ShareStore> = Lazy::new(|| {
+    let links: HashMap<String, ShareLink> = fs::read_to_string(SHARES_PATH)
+        .ok()
+        .and_then(|content| serde_json::from_str::<Vec<ShareLink>>(&content).ok())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|link| (link.token.clone(), link))
+        .collect();
+    ShareStore { links: Mutex::new(links) }
+});
+
+impl ShareStore {
+    fn save(links: &HashMap<String, ShareLink>) {
+        let list: Vec<&ShareLink> = links.values().collect();
+        match serde_json::to_string_pretty(&list) {
+            Ok(json) => {
+                if let Err(e) = fs::write(SHARES_PATH, json) {
+                    println!("ERROR! Could not save {}: {}", SHARES_PATH, e);
+                }
+            }
+            Err(e) => println!("ERROR! Could not save {}: {}", SHARES_PATH, e),
+        }
+    }
+
+    fn insert(&self, link: ShareLink) {
+        let mut links = self.links.lock().unwrap();
+        links.insert(link.token.clone(), link);
+        Self::save(&links);
+    }
+
+    ///Finds a link that is still usable. Dead links look the same as missing ones.
+    fn get(&self, token: &str) -> Option<ShareLink> {
+        let now = chrono::Utc::now().timestamp();
+        self.links.lock().unwrap().get(token).filter(|l| !l.is_dead(now)).cloned()
+    }
+
+    ///Finds a link `ip` can still download through, for the public side.
+    fn open(&self, token: &str, ip: IpAddr) -> Option<ShareLink> {
+        let now = chrono::Utc::now().timestamp();
+        self.links.lock().unwrap().get(token).filter(|l| l.lets_in(ip, now)).cloned()
+    }
+
+    ///Counts a download against the link. Checks again under the lock so two people racing for the last
+    ///download can't both get it.
+    ///
+    /// * `follow_up` - a Range request that doesn't start at the beginning of the file. With a RangePass from
+    ///   this client it's the rest of a download that already counted. Without one it counts like any other.
+    fn claim(&self, token: &str, ip: IpAddr, follow_up: bool) -> Option<ShareLink> {
+        let now = chrono::Utc::now().timestamp();
+        let mut links = self.links.lock().unwrap();
+        let link = links.get_mut(token).filter(|l| l.lets_in(ip, now))?;
+        if follow_up && link.has_pass(ip, now) {
+            return Some(link.clone());
+        }
+        if link.is_used_up() {
+            return None;
+        }
+        link.downloads += 1;
+        link.passes.retain(|pass| pass.ip != ip && now < pass.until);
+        link.passes.push(RangePass { ip, until: now.saturating_add(RANGE_PASS_SECS) });
+        let claimed = link.clone();
+        Self::save(&links);
+        Some(claimed)
+    }
+
+    ///Lets a browser that got the password right past the password page for a while. Returns the key for its cookie.
+    fn unlock(&self, token: &str) -> Option<String> {
+        let key = session::random_token().ok()?;
+        let now = chrono::Utc::now().timestamp();
+        let mut links = self.links.lock().unwrap();
+        let link = links.get_mut(token)?;
+        link.unlocks.retain(|unlock| now < unlock.until);
+        link.unlocks.push(Unlock { key: key.clone(), until: now.saturating_add(UNLOCK_SECS) });
+        Self::save(&links);
+        Some(key)
+    }
+
+    fn remove(&self, token: &str) -> Option<ShareLink> {
+        let mut links = self.links.lock().unwrap();
+        let removed = links.remove(token);
+        if removed.is_some() {
+            Self::save(&links);
+        }
+        removed
+    }
+
+    ///Keeps links pointing at a file or folder when it gets renamed or moved.
+    ///
+    /// * `from` / `to` - paths from the top of uploads
+    pub fn moved(&self, from: &str, to: &str) {
+        let mut links = self.links.lock().unwrap();
+        let mut changed = false;
+        for link in links.values_mut() {
+            if let Ok(rest) = FsPath::new(&link.file).strip_prefix(from) {
+                link.file = files::display_path(&FsPath::new(to).join(rest));
+                changed = true;
+            }
+        }
+        if changed {
+            Self::save(&links);
+        }
+    }
+
+    ///Drops the links to a file or folder that was deleted, so a new file under its name doesn't get handed out.
+    pub fn removed(&self, key: &str) {
+        let mut links = self.links.lock().unwrap();
+        let before = links.len();
+        links.retain(|_, link| !FsPath::new(&link.file).starts_with(key));
+        if links.len() < before {
+            Self::save(&links);
+        }
+    }
+
+    ///The links that still work, to files `user` is allowed to see. Only their own links come with the token,
+    ///or every link for an admin.
+    fn list(&self, user: &users::AuthUser) -> Vec<ShareInfo> {
+        let now = chrono::Utc::now().timestamp();
+        let links = self.links.lock().unwrap();
+        let mut list: Vec<ShareInfo> = links
+            .values()
+            .filter(|l| !l.is_dead(now) && files::clean_path(&l.file).is_some_and(|rel| files::may_see(&rel, user)))
+            .map(|l| {
+                let mut info = ShareInfo::from(l);
+                if l.created_by != user.name && user.role != users::Role::Admin {
+                    info.token = None;
+                    info.url = None;
+                }
+                info
+            })
+            .collect();
+        list.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        list
+    }
+
+    ///Drops expired and used up links. Called from a background task in main.
+    pub fn purge_dead(&self) {
+        let now = chrono::Utc::now().timestamp();
+        let mut links = self.links.lock().unwrap();
+        let before = links.len();
+        links.retain(|_, l| !l.is_gone(now));
+        if links.len() != before {
+            Self::save(&links);
+        }
+    }
+}
+
+///The body of POST /shares.
+///
+/// * `file` - the path of the file from the top of uploads, like `photos/beach.jpg`
+/// * `expires_in_secs` - how long the link works for. Leave it out for forever.
+/// * `max_downloads` - how many times the link works. Leave it out for no limit.
+/// * `password` - optional extra password the guest has to type in
+#[derive(Deserialize)]
+pub struct CreateShare {
+    file: String,
+    expires_in_secs: Option<u64>,
+    max_downloads: Option<u32>,
+    password: Option<String>,
+}
+
+///Makes a new share link for a file in uploads.
+pub async fn create_share(
+    Extension(user): Extension<users::AuthUser>,
+    Json(request): Json<CreateShare>,
+) -> Response {
+    let Some(rel) = files::clean_path(&request.file) else {
+        println!("WARNING: Malicious path traversal attempt blocked: {}", request.file);
+        return (StatusCode::BAD_REQUEST, "Invalid filename").into_response();
+    };
+    if !files::may_see(&rel, &user) || storage::STORAGE.stat(&files::display_path(&rel)).await.is_err() {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    }
+    if request.max_downloads == Some(0) {
+        return (StatusCode::BAD_REQUEST, "max_downloads has to be at least 1").into_response();
+    }
+
+    let token = match session::random_token() {
+        Ok(token) => token,
+        Err(e) => {
+            println!("ERROR! Could not make a share token: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    //hash the link password off the async threads, same as login.
+    let password_hash = match request.password.filter(|p| !p.is_empty()) {
+        Some(link_password) => {
+            match tokio::task::spawn_blocking(move || password::hash_password(&link_password).map_err(|e| e.to_string())).await {
+                Ok(Ok(hash)) => Some(hash),
+                _ => {
+                    println!("ERROR! Could not hash a share link password.");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+        None => None,
+    };
+
+    let expires_at = request
+        .expires_in_secs
+        .map(|secs| chrono::Utc::now().timestamp().saturating_add(secs.min(i64::MAX as u64) as i64));
+
+    let link = ShareLink {
+        token,
+        file: files::display_path(&rel),
+        created_by: user.name.clone(),
+        created_at: get_time(),
+        expires_at,
+        max_downloads: request.max_downloads,
+        downloads: 0,
+        password_hash,
+        passes: vec![],
+        unlocks: vec![],
+    };
+    let info = ShareInfo::from(&link);
+    SHARES.insert(link);
+
+    println!("🔗 '{}' made a share link for '{}' on {}", user.name, info.file, get_time());
+    (StatusCode::CREATED, Json(info)).into_response()
+}
+
+///Lists the share links that still work, to files the user can see.
+pub async fn list_shares(Extension(user): Extension<users::AuthUser>) -> Json<Vec<ShareInfo>> {
+    Json(SHARES.list(&user))
+}
+
+///Turns off a share link. Only the person who made it or an admin can.
+pub async fn revoke_share(
+    Extension(user): Extension<users::AuthUser>,
+    Path(token): Path<String>,
+) -> Response {
+    let Some(link) = SHARES.get(&token) else {
+        return (StatusCode::NOT_FOUND, "Link not found").into_response();
+    };
+    if link.created_by != user.name && user.role != users::Role::Admin {
+        return (StatusCode::FORBIDDEN, "Only the person who made this link can revoke it").into_response();
+    }
+
+    SHARES.remove(&token);
+    println!("🔗 '{}' revoked the share link for '{}' on {}", user.name, link.file, get_time());
+    StatusCode::NO_CONTENT.into_response()
+}
+
+///this is a struct for the link password.
+#[derive(Deserialize)]
+pub struct SharePasswordForm {
+    password: String,
+}
+
+///The public side of a link. Streams the file, or asks for the link password first.
+///
+/// * A browser with an Unlock cookie for the link already gave the password, so it gets the file.
+pub async fn open_share(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    cookies: Cookies,
+    request_headers: HeaderMap,
+) -> Response {
+    let Some(link) = SHARES.open(&token, addr.ip()) else {
+        return (StatusCode::NOT_FOUND, "This link doesn't exist or has expired").into_response();
+    };
+    let now = chrono::Utc::now().timestamp();
+    let unlocked = cookies.get(UNLOCK_COOKIE).is_some_and(|c| link.is_unlocked(c.value(), now));
+    if link.password_hash.is_some() && !unlocked {
+        return Html(include_str!("../share.html")).into_response();
+    }
+    send_shared_file(&token, addr, &request_headers).await
+}
+
+///Checks the link password, then sends the browser back to the GET with an Unlock cookie to fetch the file.
+///
+/// * Wrong link passwords count towards the same lockout as /login.
+/// * The file comes from the GET so Range requests after it, for resuming or seeking, can work.
+pub async fn open_share_with_password(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(token): Path<String>,
+    cookies: Cookies,
+    Form(data): Form<SharePasswordForm>,
+) -> Response {
+    //a missing link isn't a wrong password, so it mustn't count towards the lockout.
+    let Some(hash) = SHARES.open(&token, addr.ip()).and_then(|l| l.password_hash) else {
+        return (StatusCode::NOT_FOUND, "This link doesn't exist or has expired").into_response();
+    };
+    if let Err(wait) = lockout::LOGIN_GUARD.begin(addr.ip()) {
+        println!("Blocked share link password from {} on {}.", addr.ip(), get_time());
+        return too_many_attempts(wait);
+    }
+
+    let correct = tokio::task::spawn_blocking(move || password::verify_password(&data.password, &hash))
+        .await
+        .unwrap_or(false);
+    if !correct {
+        println!("Wrong share link password from {} on {}", addr.ip(), get_time());
+        if let Some(wait) = lockout::LOGIN_GUARD.record_failure(addr.ip()) {
+            return too_many_attempts(wait);
+        }
+        return (StatusCode::UNAUTHORIZED, "Wrong password").into_response();
+    }
+    //a share password says nothing about the login ones, so it only takes back its own try.
+    lockout::LOGIN_GUARD.record_right_guess(addr.ip());
+
+    let Some(key) = SHARES.unlock(&token) else {
+        println!("ERROR! Could not unlock the share link for {} on {}", addr.ip(), get_time());
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let link_url = format!("/s/{}", token);
+    let cookie = Cookie::build((UNLOCK_COOKIE, key))
+        .path(link_url.clone())
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .max_age(tower_cookies::cookie::time::Duration::seconds(UNLOCK_SECS));
+    cookies.add(cookie.into());
+    Redirect::to(&link_url).into_response()
+}
+
+///Counts the download against the link and streams the file.
+///
+/// * Range requests work here too. Every request for the whole file, or for a range from the start, uses up a
+///   download. Ranges further in from the same client ride on that download's RangePass, so a video player
+///   seeking around doesn't eat through the link.
+async fn send_shared_file(token: &str, addr: SocketAddr, request_headers: &HeaderMap) -> Response {
+    //make sure the file is still there before using up a download.
+    let Some(link) = SHARES.open(token, addr.ip()) else {
+        return (StatusCode::NOT_FOUND, "This link doesn't exist or has expired").into_response();
+    };
+    let Ok(info) = storage::STORAGE.stat(&link.file).await else {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    };
+    let file_name = link.file.rsplit('/').next().unwrap_or("download").to_string();
+    //a guest over their limit shouldn't lose a download for it.
+    let Some(limiter) = throttle::Limiter::download(addr.ip(), None) else {
+        return throttle::too_busy();
+    };
+    //neither should a range past the end, which gets a 416 and no file.
+    let range_header = request_headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let ranges = range::parse_range(range_header, info.size);
+    if !request_headers.contains_key(header::IF_RANGE) && ranges == range::RangeRequest::Unsatisfiable {
+        return stream_file(&link.file, &file_name, request_headers, limiter).await;
+    }
+    //ranges come back sorted, so the first one says where the request starts.
+    let follow_up = matches!(&ranges, range::RangeRequest::Partial(parts) if parts.first().is_some_and(|(start, _)| *start > 0));
+    let Some(link) = SHARES.claim(token, addr.ip(), follow_up) else {
+        return (StatusCode::NOT_FOUND, "This link doesn't exist or has expired").into_response();
+    };
+
+    let response = stream_file(&link.file, &file_name, request_headers, limiter).await;
+    if response.status().is_success() {
+        println!("🔗 {} downloaded '{}' through a share link on {}", addr.ip(), link.file, get_time());
+    }
+    response
+}
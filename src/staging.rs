@@ -0,0 +1,191 @@
+//Atomic uploads. Files get written under a hidden name and only renamed to the real one once they are complete,
+//so nobody can list or download half a file.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tokio::fs::File;
+
+use crate::{CONFIG, files, session, versions};
+
+///Every staging file ends with this, so leftovers are easy to find.
+const STAGING_SUFFIX: &str = ".rshare-part";
+
+///What to do when an upload's name is already taken. `on_collision=` in config.ini.
+///
+/// * `Overwrite` - the new file replaces the old one
+/// * `Rename` - the new file becomes `report (1).pdf`, `report (2).pdf` and so on
+/// * `Reject` - the upload fails with 409 and the old file stays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Collision {
+    Overwrite,
+    Rename,
+    Reject,
+}
+
+impl Collision {
+    pub fn parse(text: &str) -> Option<Collision> {
+        match text.trim().to_lowercase().as_str() {
+            "overwrite" => Some(Collision::Overwrite),
+            "rename" => Some(Collision::Rename),
+            "reject" => Some(Collision::Reject),
+            _ => None,
+        }
+    }
+}
+
+///How far auto-rename counts before giving up.
+pub const MAX_RENAME: u32 = 10_000;
+
+///A file that is still being written.
+///
+/// * `staging` - the hidden file the bytes go into, in the destination's folder or the closest one above it
+///   that exists, so the rename stays on one disk
+/// * `destination` - where the file ends up once it is complete. Folders it needs get made on commit.
+/// * `committed` - set once the rename happened. Until then, dropping this deletes the staging file.
+pub struct StagedFile {
+    staging: PathBuf,
+    destination: PathBuf,
+    committed: bool,
+}
+
+impl StagedFile {
+    ///Creates the hidden staging file for a destination.
+    ///
+    /// * The name looks like `.report.pdf.1a2b3c4d.rshare-part` in the same folder, if it's there yet.
+    ///   Otherwise it goes in the closest folder above that is, so a failed upload never leaves empty folders.
+    pub async fn create(destination: &Path) -> std::io::Result<(StagedFile, File)> {
+        let outside = || std::io::Error::new(std::io::ErrorKind::InvalidInput, "folder outside uploads");
+        let parent = destination.parent().ok_or_else(outside)?;
+        let folder = files::existing_folder(parent).ok_or_else(outside)?;
+        let name = destination.file_name().ok_or_else(outside)?;
+        let staging = hidden_name(&folder.join(name))?;
+        let file = File::create(&staging).await?;
+        let staged = StagedFile { staging, destination: destination.to_path_buf(), committed: false };
+        Ok((staged, file))
+    }
+
+    ///Fsyncs the file and moves it into place. After this the file shows up in the list.
+    ///
+    /// * `file` - the handle the bytes were written through. Everything must already be flushed into it.
+    /// * If the name is taken, on_collision decides. Returns where the file ended up.
+    pub async fn commit(mut self, file: File) -> std::io::Result<PathBuf> {
+        file.sync_all().await?;
+        drop(file);
+        if let Some(parent) = self.destination.parent() {
+            files::make_folder(parent).await.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "folder outside uploads"))?;
+        }
+        let stored = place(&self.staging, &self.destination).await?;
+        self.committed = true;
+        Ok(stored)
+    }
+}
+
+impl Drop for StagedFile {
+    ///Covers errors, early returns and the client disconnecting (axum drops the handler), so no garbage is left behind.
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.staging);
+        }
+    }
+}
+
+///Whether a file name is one of ours, for a file still being written.
+pub fn is_staging_name(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(STAGING_SUFFIX)
+}
+
+///A hidden name next to `destination` for a file that isn't ready yet, like `.report.pdf.1a2b3c4d.rshare-part`.
+///
+/// * remove_stale cleans these up after a crash, so use it for anything that should never be left lying around.
+pub fn hidden_name(destination: &Path) -> std::io::Result<PathBuf> {
+    let name = destination.file_name().and_then(|n| n.to_str()).unwrap_or("upload");
+    let random = session::random_token().map_err(std::io::Error::other)?;
+    Ok(destination.with_file_name(format!(".{}.{}{}", name, &random[..8], STAGING_SUFFIX)))
+}
+
+///Moves a finished upload to its destination, following on_collision. Shared with the tus uploads.
+///
+/// * Returns where the file ended up, which is only different from `destination` with Rename.
+/// * With Reject the error is AlreadyExists.
+/// * With Overwrite the old file is kept as a version first, if max_versions allows.
+pub async fn place(from: &Path, destination: &Path) -> std::io::Result<PathBuf> {
+    match CONFIG.on_collision {
+        Collision::Overwrite => {
+            versions::VERSIONS.keep_old(destination).await?;
+            tokio::fs::rename(from, destination).await?;
+            Ok(destination.to_path_buf())
+        }
+        Collision::Reject => {
+            place_new(from, destination).await?;
+            Ok(destination.to_path_buf())
+        }
+        Collision::Rename => {
+            for n in 0..=MAX_RENAME {
+                let candidate = numbered(destination, n);
+                match place_new(from, &candidate).await {
+                    Ok(()) => return Ok(candidate),
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(std::io::ErrorKind::AlreadyExists.into())
+        }
+    }
+}
+
+///`report.pdf` with n = 2 is `report (2).pdf`. n = 0 is the name as it is.
+pub fn numbered(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("upload");
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{} ({}).{}", stem, n, ext),
+        None => format!("{} ({})", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+///Moves `from` to `to` only if nothing is there yet.
+///
+/// * A hard link fails if the name is taken, so two uploads finishing at once can't both win.
+async fn place_new(from: &Path, to: &Path) -> std::io::Result<()> {
+    match tokio::fs::hard_link(from, to).await {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(from).await;
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(e),
+        //some drives (FAT, exFAT) can't hard link. check then rename is good enough there.
+        Err(_) => {
+            if tokio::fs::symlink_metadata(to).await.is_ok() {
+                return Err(std::io::ErrorKind::AlreadyExists.into());
+            }
+            tokio::fs::rename(from, to).await
+        }
+    }
+}
+
+///Deletes staging files left over from a crash or power cut. Called once at startup.
+///
+/// * `root` - the uploads folder. Goes into subfolders too, but leaves the resumable uploads in .tus alone.
+/// * Returns how many files were removed.
+pub fn remove_stale(root: &Path) -> usize {
+    let mut removed = 0;
+    let Ok(entries) = fs::read_dir(root) else {
+        return 0;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() && name != ".tus" => removed += remove_stale(&path),
+            Ok(t) if t.is_file() && is_staging_name(&name) => {
+                removed += usize::from(fs::remove_file(&path).is_ok());
+            }
+            _ => {}
+        }
+    }
+    removed
+}
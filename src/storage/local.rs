@@ -0,0 +1,114 @@
+//The uploads folder on this machine. What rShare always did, and still the default.
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use futures_util::{FutureExt, StreamExt, future::BoxFuture};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter},
+};
+use tokio_util::io::ReaderStream;
+
+use super::{ByteStream, Entry, ObjectInfo, Storage};
+use crate::{files, staging};
+
+const CHUNK_SIZE: usize = 128 * 1024;
+
+///Files in uploads. Writes go through a staging file, so half a file never shows up under its real name.
+pub struct LocalStorage;
+
+///The real path for a key, once symlinks are followed. NotFound if it's missing or leads outside uploads.
+fn existing(key: &str) -> io::Result<PathBuf> {
+    files::inside_uploads(&Path::new(files::UPLOADS_DIR).join(key)).ok_or_else(|| io::ErrorKind::NotFound.into())
+}
+
+fn unix_secs(meta: &std::fs::Metadata) -> i64 {
+    meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs() as i64)
+}
+
+impl LocalStorage {
+    async fn write(&self, key: &str, mut body: ByteStream<'_>) -> io::Result<String> {
+        let destination = Path::new(files::UPLOADS_DIR).join(key);
+
+        //write next to the real file under a hidden name. it gets deleted if anything goes wrong,
+        //and any folders it needs only get made once it's all in.
+        let (staged, file) = staging::StagedFile::create(&destination).await?;
+        let mut writer = BufWriter::with_capacity(CHUNK_SIZE, file);
+        while let Some(chunk) = body.next().await {
+            writer.write_all(&chunk?).await?;
+        }
+        writer.flush().await?;
+        //only now does the file show up under its real name.
+        let stored_at = staged.commit(writer.into_inner()).await?;
+        files::uploads_key(&stored_at).ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn read(&self, key: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream<'static>> {
+        let mut file = File::open(existing(key)?).await?;
+        let Some((start, end)) = range else {
+            return Ok(ReaderStream::new(BufReader::with_capacity(CHUNK_SIZE, file)).boxed());
+        };
+        file.seek(SeekFrom::Start(start)).await?;
+        let part = BufReader::with_capacity(CHUNK_SIZE, file).take(end - start + 1);
+        Ok(ReaderStream::new(part).boxed())
+    }
+
+    async fn entries(&self, folder: &str) -> io::Result<Vec<Entry>> {
+        let mut dir = tokio::fs::read_dir(existing(folder)?).await?;
+        let mut entries = vec![];
+        while let Some(e) = dir.next_entry().await? {
+            //symlinks don't count as files or folders here, so they never show up.
+            let Ok(meta) = e.metadata().await else { continue };
+            let Some(name) = e.file_name().to_str().map(str::to_string) else { continue };
+            if meta.is_file() || meta.is_dir() {
+                let size = if meta.is_dir() { 0 } else { meta.len() };
+                entries.push(Entry { name, is_dir: meta.is_dir(), size, modified: unix_secs(&meta) });
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn info(&self, key: &str) -> io::Result<ObjectInfo> {
+        let meta = tokio::fs::metadata(existing(key)?).await?;
+        if !meta.is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(ObjectInfo { size: meta.len(), modified: unix_secs(&meta) })
+    }
+
+    async fn remove(&self, key: &str) -> io::Result<()> {
+        let path = existing(key)?;
+        match path.is_dir() {
+            true => tokio::fs::remove_dir_all(path).await,
+            false => tokio::fs::remove_file(path).await,
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put<'a>(&'a self, key: &'a str, body: ByteStream<'a>) -> BoxFuture<'a, io::Result<String>> {
+        self.write(key, body).boxed()
+    }
+
+    fn get<'a>(&'a self, key: &'a str, range: Option<(u64, u64)>) -> BoxFuture<'a, io::Result<ByteStream<'static>>> {
+        self.read(key, range).boxed()
+    }
+
+    fn list<'a>(&'a self, folder: &'a str) -> BoxFuture<'a, io::Result<Vec<Entry>>> {
+        self.entries(folder).boxed()
+    }
+
+    fn stat<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<ObjectInfo>> {
+        self.info(key).boxed()
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.remove(key).boxed()
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(Path::new(files::UPLOADS_DIR).join(key))
+    }
+}
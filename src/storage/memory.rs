@@ -0,0 +1,232 @@
+//Everything in RAM, gone on restart. For trying rShare out without touching the disk, and for tests.
+use std::{collections::BTreeMap, io, sync::Mutex};
+use axum::body::Bytes;
+use futures_util::{FutureExt, StreamExt, future::BoxFuture};
+
+use super::{ByteStream, Entry, ObjectInfo, Storage, folder_prefix, pick_key};
+use crate::staging::Collision;
+
+///Files by key, with when they were stored in unix seconds. Folders are just the `/`s in the keys.
+///
+/// * `collision` - on_collision, for when a key is taken
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<String, (Bytes, i64)>>,
+    collision: Collision,
+}
+
+impl MemoryStorage {
+    pub fn new(collision: Collision) -> Self {
+        MemoryStorage { files: Mutex::default(), collision }
+    }
+
+    async fn write(&self, key: &str, mut body: ByteStream<'_>) -> io::Result<String> {
+        //all of it first, so a body that fails halfway never leaves half a file.
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+        let now = chrono::Utc::now().timestamp();
+        //the key gets picked and filled under one lock, so two uploads of one name can't both get it.
+        let mut files = self.files.lock().unwrap();
+        let key = pick_key(self.collision, key, |k| files.contains_key(k))?;
+        files.insert(key.clone(), (Bytes::from(bytes), now));
+        Ok(key)
+    }
+
+    fn read(&self, key: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream<'static>> {
+        let (bytes, _) = self.files.lock().unwrap().get(key).cloned().ok_or(io::ErrorKind::NotFound)?;
+        let part = match range {
+            Some((start, end)) if start < bytes.len() as u64 => bytes.slice(start as usize..(end as usize + 1).min(bytes.len())),
+            Some(_) => Bytes::new(),
+            None => bytes,
+        };
+        Ok(futures_util::stream::once(async move { Ok(part) }).boxed())
+    }
+
+    fn entries(&self, folder: &str) -> io::Result<Vec<Entry>> {
+        let prefix = folder_prefix(folder);
+        let files = self.files.lock().unwrap();
+        let mut entries: Vec<Entry> = vec![];
+        for (key, (bytes, modified)) in files.range(prefix.clone()..).take_while(|(key, _)| key.starts_with(&prefix)) {
+            let rest = &key[prefix.len()..];
+            match rest.split_once('/') {
+                //something deeper down. the first part is a folder in this one.
+                Some((sub, _)) => {
+                    if entries.last().is_none_or(|last| !(last.is_dir && last.name == sub)) {
+                        entries.push(Entry { name: sub.to_string(), is_dir: true, size: 0, modified: *modified });
+                    }
+                }
+                None => entries.push(Entry { name: rest.to_string(), is_dir: false, size: bytes.len() as u64, modified: *modified }),
+            }
+        }
+        //a folder only exists while something is in it, except the top.
+        if entries.is_empty() && !prefix.is_empty() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(entries)
+    }
+
+    fn info(&self, key: &str) -> io::Result<ObjectInfo> {
+        let files = self.files.lock().unwrap();
+        let (bytes, modified) = files.get(key).ok_or(io::ErrorKind::NotFound)?;
+        Ok(ObjectInfo { size: bytes.len() as u64, modified: *modified })
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        if files.remove(key).is_some() {
+            return Ok(());
+        }
+        let prefix = folder_prefix(key);
+        let before = files.len();
+        files.retain(|k, _| !k.starts_with(&prefix));
+        match files.len() < before {
+            true => Ok(()),
+            false => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put<'a>(&'a self, key: &'a str, body: ByteStream<'a>) -> BoxFuture<'a, io::Result<String>> {
+        self.write(key, body).boxed()
+    }
+
+    fn get<'a>(&'a self, key: &'a str, range: Option<(u64, u64)>) -> BoxFuture<'a, io::Result<ByteStream<'static>>> {
+        futures_util::future::ready(self.read(key, range)).boxed()
+    }
+
+    fn list<'a>(&'a self, folder: &'a str) -> BoxFuture<'a, io::Result<Vec<Entry>>> {
+        futures_util::future::ready(self.entries(folder)).boxed()
+    }
+
+    fn stat<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<ObjectInfo>> {
+        futures_util::future::ready(self.info(key)).boxed()
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        futures_util::future::ready(self.remove(key)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///A body that hands over one part at a time and lets other tasks run in between, like a real upload.
+    fn body(parts: &[&'static str]) -> ByteStream<'static> {
+        futures_util::stream::iter(parts.to_vec())
+            .then(|part| async move {
+                tokio::task::yield_now().await;
+                Ok(Bytes::from_static(part.as_bytes()))
+            })
+            .boxed()
+    }
+
+    async fn read_all(storage: &MemoryStorage, key: &str, range: Option<(u64, u64)>) -> String {
+        let mut stream = storage.get(key, range).await.unwrap();
+        let mut bytes = vec![];
+        while let Some(chunk) = stream.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn names(entries: &[Entry]) -> Vec<(String, bool)> {
+        entries.iter().map(|e| (e.name.clone(), e.is_dir)).collect()
+    }
+
+    #[tokio::test]
+    async fn put_then_get_whole_and_ranges() {
+        let storage = MemoryStorage::new(Collision::Rename);
+        assert_eq!(storage.put("a.txt", body(&["hello ", "world"])).await.unwrap(), "a.txt");
+        assert_eq!(read_all(&storage, "a.txt", None).await, "hello world");
+        assert_eq!(read_all(&storage, "a.txt", Some((6, 10))).await, "world");
+        //a range running off the end stops at the end, one starting past it is empty.
+        assert_eq!(read_all(&storage, "a.txt", Some((6, 100))).await, "world");
+        assert_eq!(read_all(&storage, "a.txt", Some((50, 60))).await, "");
+        assert!(storage.get("missing.txt", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_body_stores_nothing() {
+        let storage = MemoryStorage::new(Collision::Rename);
+        let broken = futures_util::stream::iter(vec![Ok(Bytes::from_static(b"half")), Err(io::ErrorKind::ConnectionReset.into())]).boxed();
+        let err = storage.put("a.txt", broken).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(storage.stat("a.txt").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn list_shows_files_and_folders() {
+        let storage = MemoryStorage::new(Collision::Rename);
+        for key in ["top.txt", "photos/2024/beach.jpg", "photos/2024/sea.jpg", "photos/cat.jpg"] {
+            storage.put(key, body(&["x"])).await.unwrap();
+        }
+        let top = storage.list("").await.unwrap();
+        assert_eq!(names(&top), vec![("photos".to_string(), true), ("top.txt".to_string(), false)]);
+        let photos = storage.list("photos").await.unwrap();
+        assert_eq!(names(&photos), vec![("2024".to_string(), true), ("cat.jpg".to_string(), false)]);
+        assert_eq!(names(&storage.list("photos/2024/").await.unwrap()).len(), 2);
+        assert_eq!(storage.list("nothing").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        //a file is not a folder.
+        assert_eq!(storage.list("top.txt").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn stat_knows_files_only() {
+        let storage = MemoryStorage::new(Collision::Rename);
+        storage.put("docs/a.txt", body(&["12345"])).await.unwrap();
+        assert_eq!(storage.stat("docs/a.txt").await.unwrap().size, 5);
+        assert_eq!(storage.stat("docs").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.stat("docs/b.txt").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn delete_files_and_folders() {
+        let storage = MemoryStorage::new(Collision::Rename);
+        for key in ["docs/a.txt", "docs/deep/b.txt", "docs2/c.txt"] {
+            storage.put(key, body(&["x"])).await.unwrap();
+        }
+        storage.delete("docs/a.txt").await.unwrap();
+        assert!(storage.stat("docs/a.txt").await.is_err());
+        assert!(storage.stat("docs/deep/b.txt").await.is_ok());
+        //a folder goes with everything in it, and only that folder, not one whose name starts the same.
+        storage.delete("docs").await.unwrap();
+        assert!(storage.stat("docs/deep/b.txt").await.is_err());
+        assert!(storage.stat("docs2/c.txt").await.is_ok());
+        assert_eq!(storage.delete("docs").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn collisions_follow_the_policy() {
+        let storage = MemoryStorage::new(Collision::Rename);
+        storage.put("a.txt", body(&["one"])).await.unwrap();
+        assert_eq!(storage.put("a.txt", body(&["two"])).await.unwrap(), "a (1).txt");
+        assert_eq!(read_all(&storage, "a.txt", None).await, "one");
+
+        let storage = MemoryStorage::new(Collision::Reject);
+        storage.put("a.txt", body(&["one"])).await.unwrap();
+        assert_eq!(storage.put("a.txt", body(&["two"])).await.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        let storage = MemoryStorage::new(Collision::Overwrite);
+        storage.put("a.txt", body(&["one"])).await.unwrap();
+        assert_eq!(storage.put("a.txt", body(&["two"])).await.unwrap(), "a.txt");
+        assert_eq!(read_all(&storage, "a.txt", None).await, "two");
+    }
+
+    #[tokio::test]
+    async fn uploads_of_one_name_at_once_dont_collide() {
+        let storage = MemoryStorage::new(Collision::Rename);
+        let puts = (0..20).map(|_| storage.put("same.txt", body(&["a", "b", "c"])));
+        let mut keys: Vec<String> = futures_util::future::join_all(puts).await.into_iter().map(Result::unwrap).collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 20);
+
+        let storage = MemoryStorage::new(Collision::Reject);
+        let puts = (0..20).map(|_| storage.put("same.txt", body(&["a", "b", "c"])));
+        let stored = futures_util::future::join_all(puts).await.into_iter().filter(Result::is_ok).count();
+        assert_eq!(stored, 1);
+    }
+}
@@ -0,0 +1,147 @@
+//Where the bytes of uploads actually live. `storage=` in config.ini picks one:
+//local (the uploads folder, the default), memory (gone on restart, for trying things out and for tests),
+//or s3 (any S3 compatible object store, like MinIO or the one on a NAS).
+//Uploading, listing, downloading, sharing and deleting go through here. The trash, versions, dedup, checksums
+//and rename/move work on the uploads folder itself and only happen with local storage.
+//Resumable uploads always collect their parts on disk in uploads/.tus and go into storage once they're done.
+mod local;
+mod memory;
+mod s3;
+
+use std::{io, path::{Path, PathBuf}};
+use axum::body::Bytes;
+use futures_util::{future::BoxFuture, stream::BoxStream};
+use once_cell::sync::Lazy;
+
+pub use s3::S3Settings;
+
+use crate::{CONFIG, files, staging};
+
+///The bytes of a file, a chunk at a time.
+pub type ByteStream<'a> = BoxStream<'a, io::Result<Bytes>>;
+
+///Which backend `storage=` picked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageKind {
+    Local,
+    Memory,
+    S3,
+}
+
+impl StorageKind {
+    pub fn parse(text: &str) -> Option<StorageKind> {
+        match text.trim().to_lowercase().as_str() {
+            "local" => Some(StorageKind::Local),
+            "memory" => Some(StorageKind::Memory),
+            "s3" => Some(StorageKind::S3),
+            _ => None,
+        }
+    }
+}
+
+///What stat knows about a stored file.
+///
+/// * `modified` - unix seconds
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub modified: i64,
+}
+
+///One thing in a folder.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: i64,
+}
+
+///A place to keep uploads. Keys are paths from the top of uploads, like `photos/2024/beach.jpg`.
+///
+///Keys come in already checked by files::clean_path, so backends don't need to worry about `..`.
+pub trait Storage: Send + Sync {
+    ///Stores a file, following on_collision if the key is taken.
+    ///
+    /// * `body` - the bytes. If it gives an error, nothing gets stored and that error comes back as it is.
+    /// * Returns the key the file ended up under, which with on_collision rename may not be `key`.
+    fn put<'a>(&'a self, key: &'a str, body: ByteStream<'a>) -> BoxFuture<'a, io::Result<String>>;
+
+    ///Reads a file, or just `range` of it. The range is inclusive on both ends, like in a Range header.
+    fn get<'a>(&'a self, key: &'a str, range: Option<(u64, u64)>) -> BoxFuture<'a, io::Result<ByteStream<'static>>>;
+
+    ///What's in a folder, "" for the top. Hidden names come back too, the file list skips them.
+    ///
+    /// * NotFound if there is no such folder.
+    fn list<'a>(&'a self, folder: &'a str) -> BoxFuture<'a, io::Result<Vec<Entry>>>;
+
+    ///Size and modified time of a file. NotFound for folders and missing files alike.
+    fn stat<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<ObjectInfo>>;
+
+    ///Deletes a file for good, or a folder with everything in it.
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    ///The file on disk behind a key, for the features that need a real file. None unless this is local storage.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+///The backend from config.ini. Made on first use.
+pub static STORAGE: Lazy<Box<dyn Storage>> = Lazy::new(|| match CONFIG.storage {
+    StorageKind::Local => Box::new(local::LocalStorage),
+    StorageKind::Memory => Box::new(memory::MemoryStorage::new(CONFIG.on_collision)),
+    StorageKind::S3 => Box::new(s3::S3Storage::new(CONFIG.s3.clone(), CONFIG.on_collision)),
+});
+
+///If uploads are in the uploads folder, so the features that need real files can run.
+pub fn is_local() -> bool {
+    STORAGE.local_path("").is_some()
+}
+
+///Checks if an upload to `key` is going to be turned away by on_collision reject,
+///so it can fail before the bytes come in.
+pub async fn would_reject(key: &str) -> bool {
+    CONFIG.on_collision == staging::Collision::Reject && STORAGE.stat(key).await.is_ok()
+}
+
+///The keys a new file can go under, in the order on_collision tries them. Only rename has more than one.
+fn candidates(collision: staging::Collision, key: &str) -> impl Iterator<Item = String> + '_ {
+    let last = if collision == staging::Collision::Rename { staging::MAX_RENAME } else { 0 };
+    (0..=last).map(move |n| files::display_path(&staging::numbered(Path::new(key), n)))
+}
+
+///Picks the key a new file goes under, for the backends that can't do what staging::place does.
+///
+/// * `taken` - if a key already has a file. Call this while holding whatever stops other uploads from storing,
+///   or two uploads of one name at the same moment can both think it's free.
+fn pick_key(collision: staging::Collision, key: &str, mut taken: impl FnMut(&str) -> bool) -> io::Result<String> {
+    if collision == staging::Collision::Overwrite {
+        return Ok(key.to_string());
+    }
+    candidates(collision, key).find(|candidate| !taken(candidate)).ok_or_else(|| io::ErrorKind::AlreadyExists.into())
+}
+
+///pick_key for a store that can only be asked one key at a time.
+///
+/// * Checked before the bytes go up, so two uploads of one name at the same moment can both think it's free.
+///   S3 has no way to put only if a key is missing that every compatible store supports.
+async fn free_key(storage: &dyn Storage, collision: staging::Collision, key: &str) -> io::Result<String> {
+    if collision == staging::Collision::Overwrite {
+        return Ok(key.to_string());
+    }
+    for candidate in candidates(collision, key) {
+        if storage.stat(&candidate).await.is_err() {
+            return Ok(candidate);
+        }
+    }
+    Err(io::ErrorKind::AlreadyExists.into())
+}
+
+///`photos` gives `photos/`, so a listing only matches what's inside it. "" stays "".
+fn folder_prefix(folder: &str) -> String {
+    match folder.trim_matches('/') {
+        "" => String::new(),
+        folder => format!("{}/", folder),
+    }
+}
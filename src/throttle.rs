@@ -0,0 +1,368 @@
+//Speed and concurrency limits from config.ini, speeds in bytes per second, 0 = unlimited.
+//upload_speed and download_speed are for the whole server. every transfer draws from the same bucket,
+//so five uploads at once still only get upload_speed between them.
+//upload_speed_per_client and download_speed_per_client cap a single IP on top of that,
+//and limit= lines override those for an IP, a user or guests.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use axum::{
+    body::{Body, Bytes},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures_util::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+
+use crate::{CONFIG, parse_math_string};
+
+///What a client that is over its limit gets told to wait, in seconds.
+const BUSY_RETRY_SECS: u64 = 10;
+
+///A token bucket. Every byte costs one token and tokens come back at `rate` per second.
+///
+/// * `rate` - bytes per second
+/// * `tokens` - what is left right now. Goes negative when a transfer takes more than is there,
+///   and everyone after it waits until the debt is paid off. That is what keeps the total at `rate`.
+/// * `last` - when tokens were last topped up
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket { rate: rate as f64, state: Mutex::new((burst(rate as f64), Instant::now())) }
+    }
+
+    ///Takes `bytes` tokens and says how long to wait before sending them.
+    fn reserve(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        //top up for the time that went by, but an idle bucket only saves up a little.
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(burst(self.rate));
+        *last = now;
+        *tokens -= bytes as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+///How much an idle bucket can save up. A quarter second, so a burst never goes far over the limit.
+fn burst(rate: f64) -> f64 {
+    rate / 4.0
+}
+
+///Who a limit= line is for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleTarget {
+    Ip(IpAddr),
+    User(String),
+    ///Anyone coming in through a share or drop box link without logging in.
+    Guests,
+}
+
+///The limits for one direction. None means the line didn't say, so the usual setting applies.
+///
+/// * `at_once` - how many transfers can run at the same time. A 0 in config.ini means no cap, so it ends up None.
+/// * `speed` - bytes per second for this client, 0 for no cap of its own
+#[derive(Debug, Clone, Default)]
+pub struct ClientLimit {
+    pub at_once: Option<usize>,
+    pub speed: Option<u64>,
+}
+
+///One limit= line from config.ini.
+///
+/// * `unlimited` - skips every limit, the server total too
+#[derive(Debug, Clone)]
+pub struct LimitRule {
+    pub target: RuleTarget,
+    pub upload: ClientLimit,
+    pub download: ClientLimit,
+    pub unlimited: bool,
+}
+
+impl LimitRule {
+    ///Reads the part after `limit=`, like `guests downloads=2 download_speed=5*1024*1024`.
+    ///
+    /// * Returns None if the line makes no sense, so a typo never silently lifts a limit.
+    pub fn parse(line: &str) -> Option<LimitRule> {
+        let mut words = line.split_whitespace();
+        let target = match words.next()? {
+            "guests" => RuleTarget::Guests,
+            who => match who.strip_prefix("user:") {
+                Some(name) if !name.is_empty() => RuleTarget::User(name.to_string()),
+                Some(_) => return None,
+                None => RuleTarget::Ip(who.parse().ok()?),
+            },
+        };
+        let mut rule = LimitRule { target, upload: ClientLimit::default(), download: ClientLimit::default(), unlimited: false };
+        for word in words {
+            if word == "unlimited" {
+                rule.unlimited = true;
+                continue;
+            }
+            let (key, val) = word.split_once('=')?;
+            //parse_math_string needs a fallback. u64::MAX never comes out of a real setting, so it marks a bad number.
+            let number = Some(parse_math_string(val, u64::MAX)).filter(|n| *n != u64::MAX)?;
+            match key {
+                //0 is no cap, like everywhere else in config.ini. a cap of 0 would just block everything.
+                "uploads" => rule.upload.at_once = (number > 0).then_some(number as usize),
+                "downloads" => rule.download.at_once = (number > 0).then_some(number as usize),
+                "upload_speed" => rule.upload.speed = Some(number),
+                "download_speed" => rule.download.speed = Some(number),
+                _ => return None,
+            }
+        }
+        Some(rule)
+    }
+
+    fn matches(&self, ip: IpAddr, user: Option<&str>) -> bool {
+        match &self.target {
+            RuleTarget::Ip(rule_ip) => *rule_ip == ip,
+            RuleTarget::User(name) => user == Some(name.as_str()),
+            RuleTarget::Guests => user.is_none(),
+        }
+    }
+}
+
+///One client's share of a direction.
+///
+/// * `bucket` - its own speed cap, if it has one
+/// * `active` - how many of its transfers are running right now
+struct Client {
+    bucket: Option<TokenBucket>,
+    active: AtomicUsize,
+}
+
+///Which rule matched (None for no rule) and the IP or user name it goes by.
+type ClientKey = (Option<usize>, String);
+
+///Everything for uploads, or everything for downloads.
+///
+/// * `total` - the bucket for the whole server
+/// * `clients` - made when a client starts a transfer, dropped by purge_idle once nothing uses them
+/// * `default_speed` - the per client cap for anyone whose rule doesn't set one
+/// * `side` - picks this direction's half of a rule
+struct Direction {
+    total: Option<TokenBucket>,
+    clients: Mutex<HashMap<ClientKey, Arc<Client>>>,
+    default_speed: u64,
+    side: fn(&LimitRule) -> &ClientLimit,
+}
+
+static UPLOADS: Lazy<Direction> = Lazy::new(|| Direction {
+    total: bucket(CONFIG.upload_speed_bps),
+    clients: Mutex::new(HashMap::new()),
+    default_speed: CONFIG.upload_speed_per_client_bps,
+    side: |rule| &rule.upload,
+});
+static DOWNLOADS: Lazy<Direction> = Lazy::new(|| Direction {
+    total: bucket(CONFIG.download_speed_bps),
+    clients: Mutex::new(HashMap::new()),
+    default_speed: CONFIG.download_speed_per_client_bps,
+    side: |rule| &rule.download,
+});
+
+fn bucket(rate: u64) -> Option<TokenBucket> {
+    (rate > 0).then(|| TokenBucket::new(rate))
+}
+
+impl Direction {
+    ///Finds the client's rule and takes one of its transfer slots.
+    ///
+    /// * Returns None when the client already has as many transfers going as its rule allows.
+    fn start(&'static self, ip: IpAddr, user: Option<&str>) -> Option<Limiter> {
+        //first matching line wins, like a firewall.
+        let found = CONFIG.limit_rules.iter().enumerate().find(|(_, rule)| rule.matches(ip, user));
+        if found.is_some_and(|(_, rule)| rule.unlimited) {
+            return Some(Limiter { total: None, client: None });
+        }
+        let limit = found.map(|(_, rule)| (self.side)(rule));
+        let speed = limit.and_then(|l| l.speed).unwrap_or(self.default_speed);
+        let at_once = limit.and_then(|l| l.at_once);
+        if speed == 0 && at_once.is_none() {
+            return Some(Limiter { total: self.total.as_ref(), client: None });
+        }
+
+        //a user rule follows the account around, everything else goes by IP.
+        let key = match (found, user) {
+            (Some((index, rule)), Some(name)) if matches!(rule.target, RuleTarget::User(_)) => (Some(index), format!("user:{}", name)),
+            (found, _) => (found.map(|(index, _)| index), ip.to_string()),
+        };
+        let client = self
+            .clients
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| Arc::new(Client { bucket: bucket(speed), active: AtomicUsize::new(0) }))
+            .clone();
+        client
+            .active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| at_once.is_none_or(|max| n < max).then_some(n + 1))
+            .ok()?;
+        Some(Limiter { total: self.total.as_ref(), client: Some(client) })
+    }
+}
+
+///The buckets one transfer has to draw from, and its slot. Made once per request.
+///
+/// * Hold on to it for the whole transfer. Dropping it gives the slot back.
+pub struct Limiter {
+    total: Option<&'static TokenBucket>,
+    client: Option<Arc<Client>>,
+}
+
+impl Limiter {
+    ///For a file coming in from `ip`.
+    ///
+    /// * `user` - who is logged in, or None for a guest on a drop box link
+    /// * Returns None if they are over their limit. Send them too_busy.
+    pub fn upload(ip: IpAddr, user: Option<&str>) -> Option<Self> {
+        UPLOADS.start(ip, user)
+    }
+
+    ///For a file going out to `ip`.
+    ///
+    /// * `user` - who is logged in, or None for a guest on a share link
+    /// * Returns None if they are over their limit. Send them too_busy.
+    pub fn download(ip: IpAddr, user: Option<&str>) -> Option<Self> {
+        DOWNLOADS.start(ip, user)
+    }
+
+    ///Waits until `bytes` more are allowed through. Call it for every chunk.
+    pub async fn take(&self, bytes: usize) {
+        //reserve from both at once and wait for the slower one, instead of queueing up one after the other.
+        let client = self.client.as_ref().and_then(|c| c.bucket.as_ref());
+        let wait = self.total.into_iter().chain(client).map(|b| b.reserve(bytes)).max().unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.total.is_none() && self.client.as_ref().is_none_or(|c| c.bucket.is_none())
+    }
+}
+
+impl Drop for Limiter {
+    fn drop(&mut self) {
+        if let Some(client) = &self.client {
+            client.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+///The 429 for a client that already has as many transfers going as it's allowed.
+pub fn too_busy() -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, BUSY_RETRY_SECS.to_string())],
+        "Too many transfers at once. Wait for one to finish and try again.",
+    )
+        .into_response()
+}
+
+///Turns a file stream into a response body that goes through the download limits.
+///
+/// * `stream` - the chunks of the file, usually a ReaderStream
+/// * `limiter` - from Limiter::download. It rides along with the body, so the slot stays taken
+///   until the last byte is out or the client hangs up.
+pub fn download_body<S>(stream: S, limiter: Limiter) -> Body
+where
+    S: Stream<Item = std::io::Result<Bytes>> + Unpin + Send + 'static,
+{
+    if limiter.is_unlimited() {
+        let stream = stream.map(move |chunk| {
+            let _slot = &limiter;
+            chunk
+        });
+        return Body::from_stream(stream);
+    }
+    let limited = futures_util::stream::unfold((stream, limiter), |(mut stream, limiter)| async move {
+        let chunk = stream.next().await?;
+        if let Ok(bytes) = &chunk {
+            limiter.take(bytes.len()).await;
+        }
+        Some((chunk, (stream, limiter)))
+    });
+    Body::from_stream(limited)
+}
+
+///Forgets the per client state nobody is using. Called from the cleanup ticker.
+pub fn purge_idle() {
+    for direction in [&*UPLOADS, &*DOWNLOADS] {
+        direction.clients.lock().unwrap().retain(|_, client| Arc::strong_count(client) > 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_rules_for_each_kind_of_client() {
+        let rule = LimitRule::parse("guests downloads=2 download_speed=5*1024*1024").unwrap();
+        assert_eq!(rule.target, RuleTarget::Guests);
+        assert_eq!(rule.download.at_once, Some(2));
+        assert_eq!(rule.download.speed, Some(5 * 1024 * 1024));
+        assert_eq!(rule.upload.at_once, None);
+        assert_eq!(rule.upload.speed, None);
+        assert!(!rule.unlimited);
+
+        let rule = LimitRule::parse(" 192.168.1.50   unlimited ").unwrap();
+        assert_eq!(rule.target, RuleTarget::Ip("192.168.1.50".parse().unwrap()));
+        assert!(rule.unlimited);
+
+        let rule = LimitRule::parse("user:bob uploads=0 upload_speed=0").unwrap();
+        assert_eq!(rule.target, RuleTarget::User("bob".to_string()));
+        //0 is no cap on how many at once, and no speed cap of its own.
+        assert_eq!(rule.upload.at_once, None);
+        assert_eq!(rule.upload.speed, Some(0));
+
+        assert_eq!(LimitRule::parse("::1").unwrap().target, RuleTarget::Ip("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn malformed_limit_rules_are_turned_away() {
+        for line in [
+            "",
+            "   ",
+            "user:",
+            "bob downloads=2",
+            "300.1.1.1 downloads=2",
+            "guests downloads",
+            "guests downloads=",
+            "guests downloads=two",
+            "guests downloads=-1",
+            "guests download=2",
+            "guests downloads=2 speed=5",
+            "guests unlimitd",
+        ] {
+            assert!(LimitRule::parse(line).is_none(), "{:?} got through", line);
+        }
+    }
+
+    #[test]
+    fn limit_rules_match_their_client() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        assert!(LimitRule::parse("10.0.0.1 unlimited").unwrap().matches(ip, Some("bob")));
+        assert!(!LimitRule::parse("10.0.0.1 unlimited").unwrap().matches(other, None));
+        assert!(LimitRule::parse("user:bob unlimited").unwrap().matches(other, Some("bob")));
+        assert!(!LimitRule::parse("user:bob unlimited").unwrap().matches(ip, Some("alice")));
+        assert!(LimitRule::parse("guests unlimited").unwrap().matches(ip, None));
+        assert!(!LimitRule::parse("guests unlimited").unwrap().matches(ip, Some("bob")));
+    }
+}
@@ -0,0 +1,282 @@
+//The recycle bin. Deleting moves things into uploads/.trash instead of wiping them,
+//so a wrong click can be undone until the retention period runs out.
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use axum::{
+    extract::{Extension, Path as UrlPath},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{CONFIG, file_ops::FileOpError, files, format_time, get_time, session, users};
+
+const TRASH_PATH: &str = "trash.json";
+
+///Where trashed things sit. Hidden, so it never shows up in the list or as a download.
+fn trash_dir() -> PathBuf {
+    Path::new(files::UPLOADS_DIR).join(".trash")
+}
+
+///Where what rShare keeps about an item waits while it's in the trash, so it comes back on restore and goes on purge.
+fn trash_key(id: &str) -> String {
+    format!(".trash/{}", id)
+}
+
+///One thing in the trash, as saved in trash.json.
+///
+/// * `id` - also its name inside .trash
+/// * `original_path` - where it was, from the top of uploads. Restore puts it back there.
+/// * `deleted_at` - unix seconds
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TrashItem {
+    pub id: String,
+    pub original_path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub deleted_by: String,
+    pub deleted_at: i64,
+}
+
+impl TrashItem {
+    ///When the background task throws it away for good. None if trash_retention is 0.
+    fn purge_at(&self) -> Option<i64> {
+        (CONFIG.trash_retention_secs > 0).then(|| self.deleted_at.saturating_add(CONFIG.trash_retention_secs as i64))
+    }
+}
+
+///What the dashboard gets to see about something in the trash.
+#[derive(Serialize)]
+pub struct TrashInfo {
+    pub id: String,
+    pub original_path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub deleted_by: String,
+    pub deleted_at: String,
+    pub purge_at: Option<String>,
+}
+
+impl From<&TrashItem> for TrashInfo {
+    fn from(item: &TrashItem) -> Self {
+        TrashInfo {
+            id: item.id.clone(),
+            original_path: item.original_path.clone(),
+            is_dir: item.is_dir,
+            size: item.size,
+            deleted_by: item.deleted_by.clone(),
+            deleted_at: format_time(item.deleted_at),
+            purge_at: item.purge_at().map(format_time),
+        }
+    }
+}
+
+///Everything in the trash, keyed by id. Saved to trash.json after each change.
+pub struct TrashStore {
+    items: Mutex<HashMap<String, TrashItem>>,
+}
+
+pub static TRASH: Lazy<TrashStore> = Lazy::new(|| {
+    let items: HashMap<String, TrashItem> = fs::read_to_string(TRASH_PATH)
+        .ok()
+        .and_then(|content| serde_json::from_str::<Vec<TrashItem>>(&content).ok())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| (item.id.clone(), item))
+        .collect();
+    TrashStore { items: Mutex::new(items) }
+});
+
+impl TrashStore {
+    fn save(items: &HashMap<String, TrashItem>) {
+        let list: Vec<&TrashItem> = items.values().collect();
+        match serde_json::to_string_pretty(&list) {
+            Ok(json) => {
+                if let Err(e) = fs::write(TRASH_PATH, json) {
+                    println!("ERROR! Could not save {}: {}", TRASH_PATH, e);
+                }
+            }
+            Err(e) => println!("ERROR! Could not save {}: {}", TRASH_PATH, e),
+        }
+    }
+
+    ///Moves something from uploads into the trash. Called by delete.
+    ///
+    /// * `rel` - where it is, from the top of uploads
+    /// * `full` - the real path on disk
+    pub async fn put(&self, rel: &Path, full: &Path, user: &users::AuthUser) -> Result<(), FileOpError> {
+        let id = session::random_token().map_err(|e| FileOpError::Io(std::io::Error::other(e)))?[..16].to_string();
+        tokio::fs::create_dir_all(trash_dir()).await?;
+        let is_dir = full.is_dir();
+        let size = if is_dir { folder_size(full) } else { tokio::fs::metadata(full).await?.len() };
+        tokio::fs::rename(full, trash_dir().join(&id)).await?;
+        files::move_records(&files::display_path(rel), &trash_key(&id));
+
+        let item = TrashItem {
+            id: id.clone(),
+            original_path: files::display_path(rel),
+            is_dir,
+            size,
+            deleted_by: user.name.clone(),
+            deleted_at: chrono::Utc::now().timestamp(),
+        };
+        let mut items = self.items.lock().unwrap();
+        items.insert(id, item);
+        Self::save(&items);
+        Ok(())
+    }
+
+    ///Everything this user is allowed to see, newest first.
+    fn list(&self, user: &users::AuthUser) -> Vec<TrashInfo> {
+        let items = self.items.lock().unwrap();
+        let mut list: Vec<&TrashItem> = items
+            .values()
+            .filter(|item| files::clean_path(&item.original_path).is_some_and(|rel| files::may_see(&rel, user)))
+            .collect();
+        list.sort_by_key(|item| std::cmp::Reverse(item.deleted_at));
+        list.into_iter().map(TrashInfo::from).collect()
+    }
+
+    ///Finds an item the user is allowed to touch.
+    fn get(&self, id: &str, user: &users::AuthUser) -> Option<TrashItem> {
+        let item = self.items.lock().unwrap().get(id).cloned()?;
+        files::clean_path(&item.original_path).is_some_and(|rel| files::may_see(&rel, user)).then_some(item)
+    }
+
+    fn remove(&self, id: &str) {
+        let mut items = self.items.lock().unwrap();
+        if items.remove(id).is_some() {
+            Self::save(&items);
+        }
+    }
+
+    ///Puts something back where it was. Missing folders on the way get made again.
+    ///
+    /// * Returns Exists if something new has taken its place in the meantime.
+    async fn restore(&self, item: &TrashItem, user: &users::AuthUser) -> Result<(), FileOpError> {
+        let destination = files::upload_destination("", &item.original_path, user).await.ok_or(FileOpError::BadPath)?;
+        if tokio::fs::symlink_metadata(&destination).await.is_ok() {
+            return Err(FileOpError::Exists);
+        }
+        tokio::fs::rename(trash_dir().join(&item.id), &destination).await?;
+        files::move_records(&trash_key(&item.id), &item.original_path);
+        self.remove(&item.id);
+        Ok(())
+    }
+
+    ///Deletes an item for good.
+    async fn purge(&self, item: &TrashItem) -> Result<(), FileOpError> {
+        let path = trash_dir().join(&item.id);
+        let result = match item.is_dir {
+            true => tokio::fs::remove_dir_all(&path).await,
+            false => tokio::fs::remove_file(&path).await,
+        };
+        match result {
+            //already gone from the disk is as good as purged.
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        files::forget_records(&trash_key(&item.id));
+        self.remove(&item.id);
+        Ok(())
+    }
+
+    ///Throws away everything older than trash_retention. Called from the cleanup ticker.
+    pub async fn purge_expired(&self) {
+        let now = chrono::Utc::now().timestamp();
+        let expired: Vec<TrashItem> = self
+            .items
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|item| item.purge_at().is_some_and(|t| now >= t))
+            .cloned()
+            .collect();
+        for item in expired {
+            match self.purge(&item).await {
+                Ok(()) => println!("🗑️ '{}' was in the trash too long and is gone for good on {}", item.original_path, get_time()),
+                Err(e) => println!("ERROR! Could not empty '{}' from the trash: {}", item.original_path, e),
+            }
+        }
+    }
+}
+
+///Adds up every file in a folder, for the size in the trash list.
+fn folder_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => folder_size(&e.path()),
+            Ok(t) if t.is_file() => e.metadata().map_or(0, |m| m.len()),
+            _ => 0,
+        })
+        .sum()
+}
+
+///GET /trash
+pub async fn list_trash(Extension(user): Extension<users::AuthUser>) -> Json<Vec<TrashInfo>> {
+    Json(TRASH.list(&user))
+}
+
+///POST /trash/{id}/restore
+pub async fn restore_item(Extension(user): Extension<users::AuthUser>, UrlPath(id): UrlPath<String>) -> Response {
+    let Some(item) = TRASH.get(&id, &user) else {
+        return (StatusCode::NOT_FOUND, "Not in the trash").into_response();
+    };
+    match TRASH.restore(&item, &user).await {
+        Ok(()) => {
+            println!("♻️ '{}' restored '{}' from the trash on {}", user.name, item.original_path, get_time());
+            Json(item.original_path).into_response()
+        }
+        Err(e) => {
+            println!("❌ '{}' could not restore '{}' on {} ({}): {}", user.name, item.original_path, get_time(), e.status().as_u16(), e);
+            e.into_response()
+        }
+    }
+}
+
+///DELETE /trash/{id}. Gone for good. Only the person who deleted it or an admin can.
+pub async fn purge_item(Extension(user): Extension<users::AuthUser>, UrlPath(id): UrlPath<String>) -> Response {
+    let Some(item) = TRASH.get(&id, &user) else {
+        return (StatusCode::NOT_FOUND, "Not in the trash").into_response();
+    };
+    if item.deleted_by != user.name && user.role != users::Role::Admin {
+        return (StatusCode::FORBIDDEN, "Only the person who deleted this can empty it from the trash").into_response();
+    }
+    match TRASH.purge(&item).await {
+        Ok(()) => {
+            println!("🔥 '{}' emptied '{}' from the trash on {}", user.name, item.original_path, get_time());
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            println!("❌ '{}' could not empty '{}' from the trash on {}: {}", user.name, item.original_path, get_time(), e);
+            e.into_response()
+        }
+    }
+}
+
+///DELETE /trash. Empties the whole thing. Admins only.
+pub async fn empty_trash(Extension(user): Extension<users::AuthUser>) -> Response {
+    let items: Vec<TrashItem> = TRASH.items.lock().unwrap().values().cloned().collect();
+    let mut failed = 0;
+    for item in &items {
+        if let Err(e) = TRASH.purge(item).await {
+            println!("ERROR! Could not empty '{}' from the trash: {}", item.original_path, e);
+            failed += 1;
+        }
+    }
+    println!("🔥 '{}' emptied the trash ({} items) on {}", user.name, items.len() - failed, get_time());
+    match failed {
+        0 => StatusCode::NO_CONTENT.into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "Some things could not be deleted").into_response(),
+    }
+}
@@ -0,0 +1,471 @@
+//Resumable uploads using the tus 1.0 protocol (https://tus.io/protocols/resumable-upload).
+//Supports the core protocol plus the creation and termination extensions.
+//
+//Each upload lives in .tus inside uploads as two files: `<id>.part` with the bytes so far and
+//`<id>.json` with what it is. The size of the .part file is the offset, so a restart loses nothing.
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Extension, Path},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::{CONFIG, checksums, dedup, expiry, files, get_time, quota, session, staging, storage, throttle, upload_error::UploadError, users, versions};
+
+const TUS_VERSION: &str = "1.0.0";
+///Uploads nobody has touched in this long get thrown away.
+const ABANDONED_AFTER: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+///What we know about an upload, saved next to its bytes.
+///
+/// * `length` - the full size the client promised in Upload-Length
+/// * `key` - where it goes, from the Upload-Metadata `folder` and `filename` keys, checked like any other upload.
+///   The filename falls back to the id.
+/// * `owner` - the user who started it. Only they (or an admin) can continue it.
+/// * `sha256` - from the Upload-Metadata `sha256` key, as hex. The finished file has to match it.
+/// * `expires_in` - from the Upload-Metadata `expires_in` key, checked against expiry_choices. None is file_retention.
+#[derive(Serialize, Deserialize)]
+struct TusInfo {
+    id: String,
+    length: u64,
+    key: String,
+    owner: String,
+    created_at: String,
+    #[serde(default)]
+    sha256: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+///Ids with a PATCH going right now. Two PATCHes to one upload at once would scramble it.
+static ACTIVE: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+///Frees the upload for the next PATCH even if the handler bails out early.
+struct ActiveGuard(String);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.0);
+    }
+}
+
+fn tus_dir() -> PathBuf {
+    FsPath::new(files::UPLOADS_DIR).join(".tus")
+}
+
+fn part_path(id: &str) -> PathBuf {
+    tus_dir().join(format!("{}.part", id))
+}
+
+fn info_path(id: &str) -> PathBuf {
+    tus_dir().join(format!("{}.json", id))
+}
+
+///Ids come from us and are hex. Anything else is someone poking around.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn load_info(id: &str) -> Option<TusInfo> {
+    if !is_valid_id(id) {
+        return None;
+    }
+    let content = tokio::fs::read_to_string(info_path(id)).await.ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+async fn current_offset(id: &str) -> u64 {
+    tokio::fs::metadata(part_path(id)).await.map(|m| m.len()).unwrap_or(0)
+}
+
+///Every tus response carries Tus-Resumable.
+fn tus_response(status: StatusCode) -> Response {
+    let mut response = status.into_response();
+    response.headers_mut().insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn tus_error(status: StatusCode, message: &'static str) -> Response {
+    let mut response = (status, message).into_response();
+    response.headers_mut().insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+///Checks the client speaks our version of tus. Returns the 412 to send back if it doesn't.
+fn version_mismatch(headers: &HeaderMap) -> Option<Response> {
+    match headers.get("Tus-Resumable").and_then(|v| v.to_str().ok()) {
+        Some(TUS_VERSION) => None,
+        _ => {
+            let mut response = tus_error(StatusCode::PRECONDITION_FAILED, "Unsupported tus version");
+            response.headers_mut().insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+            Some(response)
+        }
+    }
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+///Pulls one value out of Upload-Metadata, which looks like `filename d29ybGQ=,type dGV4dA==`.
+fn metadata_value(headers: &HeaderMap, key: &str) -> Option<String> {
+    let metadata = headers.get("Upload-Metadata")?.to_str().ok()?;
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        if parts.next()? != key {
+            return None;
+        }
+        let bytes = STANDARD.decode(parts.next()?.trim()).ok()?;
+        String::from_utf8(bytes).ok()
+    })
+}
+
+///Only the person who started an upload, or an admin, gets to touch it.
+fn may_touch(user: &users::AuthUser, info: &TusInfo) -> bool {
+    info.owner == user.name || user.role == users::Role::Admin
+}
+
+///OPTIONS /tus. Tells clients what this server supports.
+pub async fn tus_options() -> Response {
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    let headers = response.headers_mut();
+    headers.insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("Tus-Extension", HeaderValue::from_static("creation,termination"));
+    headers.insert("Tus-Max-Size", HeaderValue::from(CONFIG.max_upload_size));
+    response
+}
+
+///POST /tus. Starts a new upload and hands back where to PATCH it.
+pub async fn tus_create(Extension(user): Extension<users::AuthUser>, headers: HeaderMap) -> Response {
+    if let Some(response) = version_mismatch(&headers) {
+        return response;
+    }
+    let Some(length) = header_u64(&headers, "Upload-Length") else {
+        return tus_error(StatusCode::BAD_REQUEST, "Upload-Length is required");
+    };
+    if length > CONFIG.max_upload_size {
+        println!("tus upload from '{}' too large.", user.name);
+        return tus_error(StatusCode::PAYLOAD_TOO_LARGE, "File too big");
+    }
+    if let Err(e) = quota::check(Some(&user.name), length) {
+        println!("tus upload from '{}' turned away: {}", user.name, e);
+        return tus_error(e.status(), "Not enough room for this upload");
+    }
+
+    let id = match session::random_token() {
+        Ok(token) => token,
+        Err(e) => {
+            println!("ERROR! Could not make a tus upload id: {}", e);
+            return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let filename = metadata_value(&headers, "filename").unwrap_or_else(|| id.clone());
+    //block bad names and security flaws. folders work the same as on the upload form.
+    let folder = metadata_value(&headers, "folder").unwrap_or_default();
+    let Some(key) = files::upload_key(&folder, &filename, &user) else {
+        println!("WARNING: Malicious path traversal attempt blocked: {}", filename);
+        return tus_error(StatusCode::BAD_REQUEST, "Invalid filename");
+    };
+    if storage::would_reject(&key).await {
+        return tus_error(StatusCode::CONFLICT, "A file with that name already exists");
+    }
+
+    let sha256 = metadata_value(&headers, "sha256").map(|hash| hash.trim().to_lowercase());
+    if sha256.as_ref().is_some_and(|hash| hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit())) {
+        return tus_error(StatusCode::BAD_REQUEST, "The sha256 metadata should be 64 hex characters");
+    }
+
+    let expires_in = match expiry::pick(&metadata_value(&headers, "expires_in").unwrap_or_default()) {
+        Ok(expires_in) => expires_in,
+        Err(e) => return tus_error(e.status(), "That expiry isn't one of the choices"),
+    };
+
+    let info = TusInfo { id: id.clone(), length, key, owner: user.name.clone(), created_at: get_time(), sha256, expires_in };
+    let saved = async {
+        tokio::fs::create_dir_all(tus_dir()).await?;
+        tokio::fs::File::create(part_path(&id)).await?;
+        tokio::fs::write(info_path(&id), serde_json::to_string(&info)?).await
+    };
+    if let Err(e) = saved.await {
+        println!("ERROR! Could not start tus upload '{}': {}", info.key, e);
+        return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    println!("'{}' started a resumable upload of '{}' ({} bytes) on {}", user.name, info.key, length, get_time());
+
+    //an empty file is already done.
+    let mut response = tus_response(StatusCode::CREATED);
+    if length == 0 {
+        match finish(&info).await {
+            Ok(stored_as) => stored_name_header(&mut response, &stored_as),
+            Err(e) => return tus_error(e.status(), "Could not save the upload"),
+        }
+    }
+
+    if let Ok(location) = HeaderValue::from_str(&format!("/tus/{}", id)) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
+
+///HEAD /tus/{id}. Tells a client where to resume from.
+pub async fn tus_head(
+    Extension(user): Extension<users::AuthUser>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = version_mismatch(&headers) {
+        return response;
+    }
+    let Some(info) = load_info(&id).await.filter(|info| may_touch(&user, info)) else {
+        return tus_response(StatusCode::NOT_FOUND);
+    };
+
+    let mut response = tus_response(StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert("Upload-Offset", HeaderValue::from(current_offset(&id).await));
+    headers.insert("Upload-Length", HeaderValue::from(info.length));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+///PATCH /tus/{id}. Appends bytes at Upload-Offset.
+///
+/// * Whatever arrives before a disconnect is kept, so the client can HEAD and carry on.
+/// * Once the last byte is in, the file moves into uploads and shows up in the list.
+pub async fn tus_patch(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(user): Extension<users::AuthUser>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Some(response) = version_mismatch(&headers) {
+        return response;
+    }
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if content_type != Some("application/offset+octet-stream") {
+        return tus_error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Content-Type must be application/offset+octet-stream");
+    }
+    let Some(info) = load_info(&id).await.filter(|info| may_touch(&user, info)) else {
+        return tus_response(StatusCode::NOT_FOUND);
+    };
+    let Some(client_offset) = header_u64(&headers, "Upload-Offset") else {
+        return tus_error(StatusCode::BAD_REQUEST, "Upload-Offset is required");
+    };
+
+    if !ACTIVE.lock().unwrap().insert(id.clone()) {
+        return tus_error(StatusCode::LOCKED, "This upload is already being written to");
+    }
+    let _guard = ActiveGuard(id.clone());
+
+    let mut offset = current_offset(&id).await;
+    if client_offset != offset {
+        return tus_error(StatusCode::CONFLICT, "Upload-Offset does not match");
+    }
+
+    let file = match tokio::fs::OpenOptions::new().append(true).open(part_path(&id)).await {
+        Ok(file) => file,
+        Err(e) => {
+            println!("ERROR! Could not open tus upload '{}': {}", info.key, e);
+            return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let mut writer = tokio::io::BufWriter::with_capacity(128 * 1024, file);
+
+    let mut stream = body.into_data_stream();
+    let Some(limiter) = throttle::Limiter::upload(addr.ip(), Some(&user.name)) else {
+        let mut response = throttle::too_busy();
+        response.headers_mut().insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+        return response;
+    };
+    //the bytes from earlier PATCHes aren't stored yet either, so they count against the room left too.
+    let mut reservation = quota::Reservation::start(Some(&info.owner));
+    let mut failed = None;
+    if let Err(e) = reservation.take(offset) {
+        println!("tus upload of '{}' stopped: {}", info.key, e);
+        failed = Some((e.status(), "Not enough room for this upload"));
+    }
+    while failed.is_none() && let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => {
+                //client went away. keep what we have.
+                failed = Some((UploadError::ClientGone.status(), "Upload interrupted"));
+                break;
+            }
+        };
+        if offset + chunk.len() as u64 > info.length {
+            failed = Some((StatusCode::PAYLOAD_TOO_LARGE, "More bytes than Upload-Length"));
+            break;
+        }
+        if let Err(e) = reservation.take(chunk.len() as u64) {
+            println!("tus upload of '{}' stopped: {}", info.key, e);
+            failed = Some((e.status(), "Not enough room for this upload"));
+            break;
+        }
+        limiter.take(chunk.len()).await;
+        if let Err(e) = writer.write_all(&chunk).await {
+            let error = UploadError::from(e);
+            println!("ERROR! Could not write tus upload '{}': {}", info.key, error);
+            failed = Some((error.status(), "Could not write upload"));
+            break;
+        }
+        offset += chunk.len() as u64;
+    }
+
+    //get everything that did arrive onto the disk so the offset is honest after a crash.
+    let flushed = async {
+        writer.flush().await?;
+        writer.get_ref().sync_all().await
+    };
+    if let Err(e) = flushed.await {
+        println!("ERROR! Could not save tus upload '{}': {}", info.key, e);
+        return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    drop(writer);
+
+    let offset = current_offset(&id).await;
+    if let Some((status, message)) = failed {
+        let mut response = tus_error(status, message);
+        response.headers_mut().insert("Upload-Offset", HeaderValue::from(offset));
+        return response;
+    }
+
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    if offset == info.length {
+        match finish(&info).await {
+            Ok(stored_as) => stored_name_header(&mut response, &stored_as),
+            Err(UploadError::Exists) => return tus_error(StatusCode::CONFLICT, "A file with that name already exists"),
+            Err(UploadError::ChecksumMismatch) => return tus_error(StatusCode::UNPROCESSABLE_ENTITY, "The file that arrived does not match its checksum"),
+            Err(e) => return tus_error(e.status(), "Could not save the upload"),
+        }
+    }
+    response.headers_mut().insert("Upload-Offset", HeaderValue::from(offset));
+    response
+}
+
+///DELETE /tus/{id}. The termination extension. Throws away a half done upload.
+pub async fn tus_delete(
+    Extension(user): Extension<users::AuthUser>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = version_mismatch(&headers) {
+        return response;
+    }
+    let Some(info) = load_info(&id).await.filter(|info| may_touch(&user, info)) else {
+        return tus_response(StatusCode::NOT_FOUND);
+    };
+    if ACTIVE.lock().unwrap().contains(&id) {
+        return tus_error(StatusCode::LOCKED, "This upload is being written to");
+    }
+
+    let _ = tokio::fs::remove_file(part_path(&id)).await;
+    let _ = tokio::fs::remove_file(info_path(&id)).await;
+    println!("'{}' cancelled the resumable upload of '{}' on {}", user.name, info.key, get_time());
+    tus_response(StatusCode::NO_CONTENT)
+}
+
+///Moves a finished upload into storage so it shows up in the list.
+///
+/// * on_collision applies here too. Returns the name the file was stored under.
+/// * With reject, a file that took the name in the meantime means the upload is thrown away.
+async fn finish(info: &TusInfo) -> Result<String, UploadError> {
+    //the bytes came in over many requests, maybe across restarts, so they get hashed here in one go.
+    let part = part_path(&info.id);
+    let digests = match tokio::task::spawn_blocking(move || checksums::hash_file(&part)).await {
+        Ok(Ok(digests)) => digests,
+        Ok(Err(e)) => return Err(e.into()),
+        Err(e) => return Err(UploadError::Io(std::io::Error::other(e))),
+    };
+    if let Err(error) = checksums::check(info.sha256.as_deref(), &digests) {
+        println!("❌ Resumable upload of '{}' by '{}' failed on {} ({}): {}", info.key, info.owner, get_time(), error.status().as_u16(), error);
+        let _ = tokio::fs::remove_file(part_path(&info.id)).await;
+        let _ = tokio::fs::remove_file(info_path(&info.id)).await;
+        return Err(error);
+    }
+    match store_part(info).await {
+        Ok(stored_as) => {
+            let _ = tokio::fs::remove_file(info_path(&info.id)).await;
+            if let Some(stored_at) = storage::STORAGE.local_path(&stored_as) {
+                dedup::store(&stored_at, &digests.sha256);
+                checksums::CHECKSUMS.record(&stored_at, &digests);
+                versions::VERSIONS.record_upload(&stored_at, &info.owner);
+            }
+            quota::OWNERS.record(&stored_as, Some(&info.owner), info.length);
+            expiry::EXPIRY.set(&stored_as, info.expires_in);
+            println!("\n   ⬆️ '{}' uploaded '{}' to the dashboard as '{}' on {}", info.owner, info.key, stored_as, get_time());
+            Ok(stored_as)
+        }
+        Err(e) => {
+            let error = UploadError::from(e);
+            println!("ERROR! Could not move finished upload '{}' into place: {}", info.key, error);
+            if matches!(error, UploadError::Exists) {
+                let _ = tokio::fs::remove_file(part_path(&info.id)).await;
+                let _ = tokio::fs::remove_file(info_path(&info.id)).await;
+            }
+            Err(error)
+        }
+    }
+}
+
+///Puts the finished part where it belongs and returns its key. On local storage that is just a rename.
+async fn store_part(info: &TusInfo) -> std::io::Result<String> {
+    if storage::is_local() {
+        let destination = files::key_destination(&info.key).await.ok_or(std::io::ErrorKind::InvalidInput)?;
+        let stored_at = staging::place(&part_path(&info.id), &destination).await?;
+        return files::uploads_key(&stored_at).ok_or_else(|| std::io::ErrorKind::NotFound.into());
+    }
+    let part = tokio::fs::File::open(part_path(&info.id)).await?;
+    let stored_as = storage::STORAGE.put(&info.key, ReaderStream::new(part).boxed()).await?;
+    let _ = tokio::fs::remove_file(part_path(&info.id)).await;
+    Ok(stored_as)
+}
+
+///Tells a tus client what name its finished file got, since on_collision may have changed it.
+fn stored_name_header(response: &mut Response, stored_as: &str) {
+    if let Ok(value) = HeaderValue::from_str(stored_as) {
+        response.headers_mut().insert("X-Stored-Name", value);
+    }
+}
+
+///Throws away uploads that haven't been touched in a week. Called from a background task in main.
+pub async fn purge_abandoned() {
+    let Ok(mut entries) = tokio::fs::read_dir(tus_dir()).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(id) = name.strip_suffix(".json") else {
+            continue;
+        };
+        if ACTIVE.lock().unwrap().contains(id) {
+            continue;
+        }
+        //the .part file gets touched on every PATCH, so go by that.
+        let last_touched = tokio::fs::metadata(part_path(id))
+            .await
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        if last_touched.elapsed().unwrap_or_default() > ABANDONED_AFTER {
+            let _ = tokio::fs::remove_file(part_path(id)).await;
+            let _ = tokio::fs::remove_file(info_path(id)).await;
+            println!("Removed abandoned resumable upload {} on {}", id, get_time());
+        }
+    }
+}
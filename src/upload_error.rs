@@ -0,0 +1,131 @@
+//Everything that can go wrong while a file is coming in, and what the browser gets told about it.
+use std::fmt;
+use axum::{
+    extract::multipart::MultipartError,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+use crate::get_time;
+
+///Why an upload failed.
+///
+/// * `BadRequest` - broken multipart body or a bad file name. 400.
+/// * `TooLarge` - over the config limit or a link's limit. 413.
+/// * `Exists` - the name is taken and on_collision is reject. 409.
+/// * `ChecksumMismatch` - the bytes that came in don't hash to the checksum the client sent. 422.
+/// * `ClientGone` - the browser closed the tab or lost wifi halfway. 499, like nginx.
+/// * `DiskFull` - no room left on the server. 507.
+/// * `OverQuota` - the upload would go over the server's or the user's quota. 507.
+/// * `Io` - any other disk problem. 500.
+#[derive(Debug)]
+pub enum UploadError {
+    BadRequest(&'static str),
+    TooLarge,
+    Exists,
+    ChecksumMismatch,
+    ClientGone,
+    DiskFull,
+    OverQuota,
+    Io(std::io::Error),
+}
+
+impl UploadError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UploadError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            UploadError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            UploadError::Exists => StatusCode::CONFLICT,
+            UploadError::ChecksumMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            //499 isn't in the http crate's list, but it is always a valid code.
+            UploadError::ClientGone => StatusCode::from_u16(499).unwrap_or(StatusCode::BAD_REQUEST),
+            UploadError::DiskFull | UploadError::OverQuota => StatusCode::INSUFFICIENT_STORAGE,
+            UploadError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::BadRequest(reason) => write!(f, "{}", reason),
+            UploadError::TooLarge => write!(f, "File too big"),
+            UploadError::Exists => write!(f, "A file with that name already exists"),
+            UploadError::ChecksumMismatch => write!(f, "The file that arrived does not match its checksum"),
+            UploadError::ClientGone => write!(f, "The client disconnected before the upload finished"),
+            UploadError::DiskFull => write!(f, "The server is out of disk space"),
+            UploadError::OverQuota => write!(f, "Not enough room left in the storage quota"),
+            UploadError::Io(e) => write!(f, "Disk error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        //a body stream going into storage can only fail with an io::Error, so upload errors ride inside one.
+        let e = match e.downcast::<UploadError>() {
+            Ok(inner) => return inner,
+            Err(e) => e,
+        };
+        match e.kind() {
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => UploadError::DiskFull,
+            std::io::ErrorKind::AlreadyExists => UploadError::Exists,
+            _ => UploadError::Io(e),
+        }
+    }
+}
+
+impl From<MultipartError> for UploadError {
+    ///axum reports a body over the limit as 413 and a broken body as 400.
+    ///Anything else is the stream dying, which means the client went away.
+    fn from(e: MultipartError) -> Self {
+        match e.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => UploadError::TooLarge,
+            StatusCode::BAD_REQUEST => UploadError::BadRequest("Malformed upload"),
+            _ => UploadError::ClientGone,
+        }
+    }
+}
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        //don't hand disk error details to the browser. they went to the log already.
+        let message = match &self {
+            UploadError::Io(_) => "Could not save the file".to_string(),
+            other => other.to_string(),
+        };
+        (self.status(), message).into_response()
+    }
+}
+
+///Logs a ClientGone failure if an upload gets dropped halfway.
+///
+///When a browser disconnects, axum usually just drops the handler instead of giving it an error,
+///so nothing after the current `.await` runs. This is the only place that still gets to say something.
+pub struct DisconnectGuard<'a> {
+    who: &'a str,
+    name_of_file: &'a str,
+    armed: bool,
+}
+
+impl<'a> DisconnectGuard<'a> {
+    pub fn new(who: &'a str, name_of_file: &'a str) -> Self {
+        DisconnectGuard { who, name_of_file, armed: true }
+    }
+
+    ///The upload finished one way or another and logged itself.
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for DisconnectGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            let error = UploadError::ClientGone;
+            println!("\n❌ Upload of '{}' by '{}' failed on {} ({}): {}", self.name_of_file, self.who, get_time(), error.status().as_u16(), error);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//User accounts. Lives in users.ini, one user per line.
+use std::{collections::HashMap, fmt, fs, path::Path};
+use once_cell::sync::Lazy;
+
+use crate::password;
+
+pub const USERS_PATH: &str = "users.ini";
+
+///What a user is allowed to do. Each role can do everything the ones before it can.
+///
+/// * `ReadOnly` - list and download files
+/// * `Uploader` - also upload files, and delete, rename and move the ones they uploaded
+/// * `Admin` - also the admin routes, and changing anyone's files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    ReadOnly,
+    Uploader,
+    Admin,
+}
+
+impl Role {
+    fn parse(value: &str) -> Option<Role> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "readonly" | "read-only" | "read_only" => Some(Role::ReadOnly),
+            "uploader" => Some(Role::Uploader),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::ReadOnly => "readonly",
+            Role::Uploader => "uploader",
+            Role::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+///One account from users.ini.
+///
+/// * `name` - what they type into the login page
+/// * `role` - what they can do once logged in
+/// * `password_hash` - the argon2 PHC string. Never the real password.
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    pub password_hash: String,
+}
+
+///The logged in user, put into the request extensions by require_auth so handlers know who did what.
+#[derive(Clone, Debug)]
+pub struct AuthUser {
+    pub name: String,
+    pub role: Role,
+}
+
+///Every account, keyed by username. ensure_users must run first so the file exists and is hashed.
+pub static USERS: Lazy<HashMap<String, User>> = Lazy::new(|| {
+    let content = fs::read_to_string(USERS_PATH).unwrap_or_default();
+    let mut users = HashMap::new();
+    for (line_number, line) in content.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(user)) => {
+                users.insert(user.name.clone(), user);
+            }
+            Ok(None) => {}
+            Err(e) => println!("Warning: skipping line {} of {}: {}", line_number + 1, USERS_PATH, e),
+        }
+    }
+    users
+});
+
+///Used when someone logs in with a username that doesn't exist, so that takes as long as a wrong password.
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| {
+    password::hash_password("rshare-not-a-real-user").unwrap_or_default()
+});
+
+///Reads `name:role:password` from a line of users.ini.
+///
+/// * Blank lines and lines starting with '#' are skipped.
+/// * The password part can be plaintext. ensure_users hashes it on start.
+fn parse_line(line: &str) -> Result<Option<User>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('[') {
+        return Ok(None);
+    }
+
+    //splitn because the hash could in theory have a ':' in the salt. it doesn't today but be safe.
+    let mut parts = line.splitn(3, ':');
+    let (Some(name), Some(role), Some(secret)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err("expected name:role:password".into());
+    };
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("username is empty".into());
+    }
+    let role = Role::parse(role).ok_or_else(|| format!("unknown role '{}'", role.trim()))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        return Err(format!("user '{}' has no password", name));
+    }
+
+    Ok(Some(User { name: name.to_string(), role, password_hash: secret.to_string() }))
+}
+
+///Makes sure users.ini exists and has no plaintext passwords in it.
+///
+/// * `seed_hash` - the hash from PASSWORD.env. The first run turns it into an `admin` account.
+/// * Any plaintext password in the file gets hashed in place, same as PASSWORD.env.
+pub fn ensure_users(seed_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(USERS_PATH);
+
+    if !path.exists() {
+        println!("No {} found. Making one with an 'admin' account using your PASSWORD.env password.", USERS_PATH);
+        let content = format!(
+            "# rShare users. One per line -> name:role:password\n\
+             # roles: admin, uploader, readonly\n\
+             # You can type a plaintext password here, it gets hashed the next time rShare starts.\n\
+             admin:admin:{}\n",
+            seed_hash
+        );
+        fs::write(path, content)?;
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(path)?;
+    let mut changed = false;
+    let mut lines = Vec::new();
+    for line in content.lines() {
+        match parse_line(line) {
+            Ok(Some(user)) if !password::is_hashed(&user.password_hash) => {
+                println!("Hashing the plaintext password of user '{}'...", user.name);
+                let hash = password::hash_password(&user.password_hash)?;
+                lines.push(format!("{}:{}:{}", user.name, user.role, hash));
+                changed = true;
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    if changed {
+        fs::write(path, lines.join("\n") + "\n")?;
+    }
+    Ok(())
+}
+
+///Checks a username and password.
+///
+/// * Unknown usernames still run argon2 against a dummy hash, so timing doesn't tell you which names exist.
+pub fn authenticate(name: &str, attempt: &str) -> Option<AuthUser> {
+    match USERS.get(name) {
+        Some(user) if password::verify_password(attempt, &user.password_hash) => Some(AuthUser {
+            name: user.name.clone(),
+            role: user.role,
+        }),
+        Some(_) => None,
+        None => {
+            password::verify_password(attempt, &DUMMY_HASH);
+            None
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//File history. When an upload overwrites a file, the old content is kept in uploads/.versions
+//so it can be downloaded or put back. max_versions in config.ini caps how many each file keeps.
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use axum::{
+    extract::{ConnectInfo, Extension, Path as UrlPath, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{CONFIG, checksums, dedup, files, format_time, get_time, quota, session, staging, stream_file, throttle, users};
+
+const VERSIONS_PATH: &str = "versions.json";
+
+///Where old content sits. Hidden, so it never shows up in the list or as a normal download.
+fn versions_dir() -> PathBuf {
+    Path::new(files::UPLOADS_DIR).join(".versions")
+}
+
+///One old copy of a file.
+///
+/// * `id` - also its name inside .versions
+/// * `saved_at` - unix seconds, when it got replaced
+/// * `uploaded_by` - who uploaded this content, if we know
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Version {
+    pub id: String,
+    pub saved_at: i64,
+    pub size: u64,
+    pub uploaded_by: Option<String>,
+}
+
+///Everything we know about one file's past, as saved in versions.json.
+///
+/// * `uploaded_by` - who uploaded what's there right now
+/// * `versions` - oldest first
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct History {
+    pub uploaded_by: Option<String>,
+    pub versions: Vec<Version>,
+}
+
+impl History {
+    ///Drops the oldest versions over max_versions. Their copies still need deleting.
+    fn trim(&mut self) -> Vec<Version> {
+        let extra = self.versions.len().saturating_sub(CONFIG.max_versions as usize);
+        self.versions.drain(..extra).collect()
+    }
+
+    ///Takes in the history of a file that just moved onto this one's path. That file is the one there now,
+    ///so its uploader wins, and both sets of versions are kept, oldest first, up to max_versions.
+    fn absorb(&mut self, moved: History) -> Vec<Version> {
+        self.uploaded_by = moved.uploaded_by;
+        self.versions.extend(moved.versions);
+        self.versions.sort_by_key(|v| v.saved_at);
+        self.trim()
+    }
+}
+
+///Deletes the copies of versions nobody keeps any more, off the async threads.
+fn delete_copies(dropped: Vec<Version>) {
+    if dropped.is_empty() {
+        return;
+    }
+    tokio::task::spawn_blocking(move || {
+        for version in dropped {
+            let _ = fs::remove_file(versions_dir().join(&version.id));
+        }
+    });
+}
+
+///What the dashboard gets to see about a version.
+#[derive(Serialize)]
+pub struct VersionInfo {
+    pub id: String,
+    pub saved_at: String,
+    pub size: u64,
+    pub uploaded_by: Option<String>,
+}
+
+impl From<&Version> for VersionInfo {
+    fn from(version: &Version) -> Self {
+        VersionInfo {
+            id: version.id.clone(),
+            saved_at: format_time(version.saved_at),
+            size: version.size,
+            uploaded_by: version.uploaded_by.clone(),
+        }
+    }
+}
+
+///Every file's history, keyed by its path from the top of uploads. Saved to versions.json after each change.
+pub struct VersionStore {
+    files: Mutex<HashMap<String, History>>,
+}
+
+pub static VERSIONS: Lazy<VersionStore> = Lazy::new(|| {
+    let files: HashMap<String, History> = fs::read_to_string(VERSIONS_PATH)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    VersionStore { files: Mutex::new(files) }
+});
+
+impl VersionStore {
+    fn save(files: &HashMap<String, History>) {
+        match serde_json::to_string_pretty(files) {
+            Ok(json) => {
+                if let Err(e) = fs::write(VERSIONS_PATH, json) {
+                    println!("ERROR! Could not save {}: {}", VERSIONS_PATH, e);
+                }
+            }
+            Err(e) => println!("ERROR! Could not save {}: {}", VERSIONS_PATH, e),
+        }
+    }
+
+    ///Keeps what's at `destination` as a version before it gets overwritten. Does nothing with max_versions at 0.
+    ///
+    /// * Hard links when it can, so the file never goes missing in between. Copies on drives that can't.
+    pub async fn keep_old(&self, destination: &Path) -> std::io::Result<()> {
+        if CONFIG.max_versions == 0 {
+            return Ok(());
+        }
+        let Ok(meta) = tokio::fs::metadata(destination).await else {
+            return Ok(());
+        };
+        if !meta.is_file() {
+            return Ok(());
+        }
+        let id = session::random_token().map_err(std::io::Error::other)?[..16].to_string();
+        tokio::fs::create_dir_all(versions_dir()).await?;
+        let saved = versions_dir().join(&id);
+        if tokio::fs::hard_link(destination, &saved).await.is_err() {
+            tokio::fs::copy(destination, &saved).await?;
+        }
+
+        let dropped = {
+            let mut files = self.files.lock().unwrap();
+            let history = files.entry(files::uploads_key(destination).unwrap_or_default()).or_default();
+            history.versions.push(Version {
+                id,
+                saved_at: chrono::Utc::now().timestamp(),
+                size: meta.len(),
+                uploaded_by: history.uploaded_by.take(),
+            });
+            //over the cap, the oldest go.
+            let dropped = history.trim();
+            Self::save(&files);
+            dropped
+        };
+        delete_copies(dropped);
+        Ok(())
+    }
+
+    ///Notes who uploaded what's now at `path`, so the version made when it gets overwritten can say.
+    pub fn record_upload(&self, path: &Path, who: &str) {
+        if CONFIG.max_versions == 0 {
+            return;
+        }
+        let mut files = self.files.lock().unwrap();
+        files.entry(files::uploads_key(path).unwrap_or_default()).or_default().uploaded_by = Some(who.to_string());
+        Self::save(&files);
+    }
+
+    ///Keeps the history with a file or folder when it gets renamed, moved, trashed or restored.
+    ///
+    /// * `from` / `to` - paths from the top of uploads
+    /// * A history already at `to` gets merged with the one coming in, not replaced, so no copies get lost track of.
+    pub fn moved(&self, from: &str, to: &str) {
+        let dropped = {
+            let mut files = self.files.lock().unwrap();
+            let keys: Vec<String> = files.keys().filter(|k| Path::new(k).starts_with(from)).cloned().collect();
+            if keys.is_empty() {
+                return;
+            }
+            let mut dropped = vec![];
+            for old in keys {
+                let Some(history) = files.remove(&old) else { continue };
+                let rest = Path::new(&old).strip_prefix(from).map(Path::to_path_buf).unwrap_or_default();
+                let new_key = files::display_path(&Path::new(to).join(rest));
+                dropped.extend(files.entry(new_key).or_default().absorb(history));
+            }
+            Self::save(&files);
+            dropped
+        };
+        delete_copies(dropped);
+    }
+
+    ///Forgets the history of a file or folder that's gone for good, and deletes its old copies.
+    pub fn removed(&self, key: &str) {
+        let dropped: Vec<Version> = {
+            let mut files = self.files.lock().unwrap();
+            let keys: Vec<String> = files.keys().filter(|k| Path::new(k).starts_with(key)).cloned().collect();
+            if keys.is_empty() {
+                return;
+            }
+            let dropped = keys.iter().filter_map(|k| files.remove(k)).flat_map(|h| h.versions).collect();
+            Self::save(&files);
+            dropped
+        };
+        delete_copies(dropped);
+    }
+
+    ///Finds a version and the file it belongs to.
+    fn find(&self, id: &str) -> Option<(String, Version)> {
+        let files = self.files.lock().unwrap();
+        files.iter().find_map(|(path, history)| history.versions.iter().find(|v| v.id == id).map(|v| (path.clone(), v.clone())))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VersionsQuery {
+    path: String,
+}
+
+///GET /versions?path=docs/report.pdf. Newest first.
+pub async fn list_versions(Extension(user): Extension<users::AuthUser>, Query(query): Query<VersionsQuery>) -> Response {
+    let Some(rel) = files::clean_path(&query.path).filter(|rel| files::may_see(rel, &user)) else {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    };
+    let history = VERSIONS.files.lock().unwrap().get(&files::display_path(&rel)).cloned().unwrap_or_default();
+    let list: Vec<VersionInfo> = history.versions.iter().rev().map(VersionInfo::from).collect();
+    Json(list).into_response()
+}
+
+///Finds a version the user is allowed to see.
+fn visible_version(id: &str, user: &users::AuthUser) -> Option<(String, Version)> {
+    let (path, version) = VERSIONS.find(id)?;
+    files::clean_path(&path).is_some_and(|rel| files::may_see(&rel, user)).then_some((path, version))
+}
+
+///GET /versions/{id}. Downloads an old copy under the file's name.
+pub async fn download_version(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(user): Extension<users::AuthUser>,
+    UrlPath(id): UrlPath<String>,
+    request_headers: HeaderMap,
+) -> Response {
+    let Some((path, version)) = visible_version(&id, &user) else {
+        return (StatusCode::NOT_FOUND, "Version not found").into_response();
+    };
+    let Some(limiter) = throttle::Limiter::download(addr.ip(), Some(&user.name)) else {
+        return throttle::too_busy();
+    };
+    let name = Path::new(&path).file_name().and_then(|n| n.to_str()).unwrap_or("download").to_string();
+    let response = stream_file(&format!(".versions/{}", version.id), &name, &request_headers, limiter).await;
+    if response.status().is_success() {
+        println!("⬇️ '{}' downloaded the version of '{}' from {} on {}", user.name, path, format_time(version.saved_at), get_time());
+    }
+    response
+}
+
+///POST /versions/{id}/restore. Makes an old copy the current one.
+///
+/// * What's there right now becomes a version first, so a restore can be undone too.
+pub async fn restore_version(Extension(user): Extension<users::AuthUser>, UrlPath(id): UrlPath<String>) -> Response {
+    let Some((path, version)) = visible_version(&id, &user) else {
+        return (StatusCode::NOT_FOUND, "Version not found").into_response();
+    };
+    let Some(destination) = files::upload_destination("", &path, &user).await else {
+        return (StatusCode::BAD_REQUEST, "Invalid path").into_response();
+    };
+
+    //copy next to the file first, then swap it in with one rename.
+    //a staging name, so it stays out of the list and a crash halfway gets cleaned up.
+    let copy = match staging::hidden_name(&destination) {
+        Ok(copy) => copy,
+        Err(e) => {
+            println!("ERROR! Could not restore '{}': {}", path, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Could not restore that version").into_response();
+        }
+    };
+    let restored = async {
+        tokio::fs::copy(versions_dir().join(&version.id), &copy).await?;
+        VERSIONS.keep_old(&destination).await?;
+        tokio::fs::rename(&copy, &destination).await
+    };
+    if let Err(e) = restored.await {
+        let _ = tokio::fs::remove_file(&copy).await;
+        println!("❌ '{}' could not restore '{}' to the version from {} on {}: {}", user.name, path, format_time(version.saved_at), get_time(), e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Could not restore that version").into_response();
+    }
+    if let Some(uploader) = &version.uploaded_by {
+        VERSIONS.record_upload(&destination, uploader);
+    }
+    //the quota should count what's there now, and for whoever uploaded it.
+    quota::OWNERS.record(&path, version.uploaded_by.as_deref(), version.size);
+    let restored_path = destination.clone();
+    let _ = tokio::task::spawn_blocking(move || {
+        if let Ok(digests) = checksums::hash_file(&restored_path) {
+            dedup::store(&restored_path, &digests.sha256);
+            checksums::CHECKSUMS.record(&restored_path, &digests);
+        }
+    })
+    .await;
+    println!("⏪ '{}' restored '{}' to the version from {} on {}", user.name, path, format_time(version.saved_at), get_time());
+    Json(path).into_response()
+}
+
+///How many old copies a file has, for the file list.
+pub fn count(path: &str) -> usize {
+    VERSIONS.files.lock().unwrap().get(path).map_or(0, |h| h.versions.len())
+}